@@ -1,47 +1,170 @@
+use std::fmt;
 use std::rc::Rc;
-use std::cell::RefCell;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cell::{Cell, RefCell};
 
-use crate::{environment::Environment, token};
+use crate::{environment::{Environment, Slot}, token};
 use crate::expression::Value;
 use crate::token::Token;
 use crate::statement::Statement;
-use crate::util::UnwindType;
-use crate::lox_class::LoxInstance;
-use crate::error_reporter::ERROR_REPORTER;
+use crate::util::{UnwindType, MAXIMUM_FRAME_COUNT};
+use crate::lox_class::{LoxClass, LoxInstance};
+use crate::vm::VmClosure;
+use crate::gc;
+use crate::gc::{Trace, Tracer};
+use crate::diagnostic::{Diagnostic, RuntimeError};
+
+// How many arguments a callable takes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    // Variadic, with a minimum number of arguments.
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Arity::Exactly(arity) => count == *arity,
+            Arity::AtLeast(arity) => count >= *arity,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exactly(arity) => write!(f, "{}", arity),
+            Arity::AtLeast(arity) => write!(f, "at least {}", arity),
+        }
+    }
+}
+
+// What sort of callable something is, which decides how it's printed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    Function,
+    Native,
+    Class,
+}
 
 pub trait Callable {
-    fn call(self: Rc<Self>, environment: Rc<RefCell<Environment>>, arguments: Vec<Value>) -> Option<Value>;
-    fn arity(&self) -> usize;
+    fn call(self: Rc<Self>, arguments: Vec<Value>) -> Result<Value, Diagnostic>;
+    fn arity(&self) -> Arity;
+    // Empty for anonymous functions.
+    fn name(&self) -> &str;
+    fn kind(&self) -> Kind;
+
+    // Classes are stored as callables; this lets us get them back out when inheriting.
+    fn as_class(self: Rc<Self>) -> Option<Rc<LoxClass>> {
+        None
+    }
+
+    // The VM calls its own closures by pushing a frame, rather than going through call().
+    fn as_vm_closure(self: Rc<Self>) -> Option<Rc<VmClosure>> {
+        None
+    }
+
+    // Callables that hold references to other heap objects need to report them to the collector.
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    // The name calls to this are shown under in stack traces. Natives don't get frames of their own,
+    // since they don't have any lines to be on.
+    fn frame_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+// Anything that can be stored in a class and bound to an instance when accessed.
+pub trait Method: Callable {
+    fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> Rc<dyn Callable>;
 }
 
-pub struct NativeClock {
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, RuntimeError>;
 
+// A global function implemented in Rust. The arity is checked before the function is called.
+pub struct NativeFunction {
+    name: String,
+    arity: Arity,
+    function: Box<NativeFn>,
 }
 
-impl NativeClock {
-    pub fn new() -> Self {
+impl NativeFunction {
+    pub fn new<F>(name: &str, arity: Arity, function: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
         Self {
-
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
         }
     }
 }
 
-impl Callable for NativeClock {
-    fn arity(&self) -> usize {
-        0
+impl Callable for NativeFunction {
+    fn arity(&self) -> Arity {
+        self.arity
     }
 
-    fn call(self: Rc<Self>, _: Rc<RefCell<Environment>>, _: Vec<Value>) -> Option<Value> {
-        if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
-            Some(Value::Number(time.as_millis() as f64 / 1000.0))
-        } else {
-            ERROR_REPORTER.lock().unwrap().runtime_error("Unable to determine offset from UNIX epoch: Time is going backwards!");
-            None
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Native
+    }
+
+    fn call(self: Rc<Self>, arguments: Vec<Value>) -> Result<Value, Diagnostic> {
+        (self.function)(&arguments).map_err(|error| Diagnostic::error(None, &format!("{}: {}", self.name, error)))
+    }
+}
+
+// A method implemented in Rust, bound to a receiver of a built-in type, e.g. `list.push`.
+pub struct NativeMethod<T> {
+    name: Token,
+    receiver: T,
+    arity: usize,
+    function: fn(&T, Vec<Value>) -> Result<Value, String>,
+}
+
+impl<T> NativeMethod<T> {
+    pub fn new(name: Token, receiver: T, arity: usize, function: fn(&T, Vec<Value>) -> Result<Value, String>) -> Self {
+        Self {
+            name,
+            receiver,
+            arity,
+            function,
         }
     }
 }
 
+impl<T: Trace> Callable for NativeMethod<T> {
+    fn arity(&self) -> Arity {
+        Arity::Exactly(self.arity)
+    }
+
+    fn name(&self) -> &str {
+        self.name.lexeme()
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Native
+    }
+
+    fn call(self: Rc<Self>, arguments: Vec<Value>) -> Result<Value, Diagnostic> {
+        (self.function)(&self.receiver, arguments).map_err(|message| Diagnostic::on_token(&self.name, &message))
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+    }
+}
+
+thread_local! {
+    // How many calls to Lox functions the tree-walker is in the middle of.
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
 pub struct LoxCallable {
     name: Token,
     params: Vec<Token>,
@@ -61,43 +184,75 @@ impl LoxCallable {
         }
     }
 
-    pub fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> LoxCallable {
-        let mut environment = Environment::with_enclosing_scope(self.closure.clone());
-        environment.define("this".to_string(), Value::Instance(instance));
-        LoxCallable::new(self.name.clone(), self.params.clone(), self.body.clone(), Rc::new(RefCell::new(environment)), self.is_initializer)
-    }
-}
-
-impl Callable for LoxCallable {
-    fn call(self: Rc<Self>, _: Rc<RefCell<Environment>>, arguments: Vec<Value>) -> Option<Value> {
-        let scoped_environment = Rc::new(RefCell::new(Environment::with_enclosing_scope(self.closure.clone())));
+    fn run(&self, arguments: Vec<Value>) -> Result<Value, Diagnostic> {
+        let scoped_environment = gc::new_environment(Environment::with_enclosing_scope(self.closure.clone()));
         for i in 0..self.params.len() {
-            scoped_environment.borrow_mut().define(self.params.get(i).unwrap().lexeme().to_string(), arguments.get(i).unwrap().clone());
+            scoped_environment.borrow_mut().define(self.params.get(i).unwrap().lexeme(), arguments.get(i).unwrap().clone());
         }
         for statement in &self.body {
             match statement.interpret(scoped_environment.clone()) {
-                Err(UnwindType::Error) => return None,
+                Err(UnwindType::Error(diagnostic)) => return Err(diagnostic),
                 Err(UnwindType::Return(value)) => {
                     if self.is_initializer {
                         // This is a bit of a hack. Let's hope resolution dosen't magically fail, or the error
                         // message will be strange!
-                        return self.closure.borrow().get_at(Some(0), &Token::new(token::Type::This, "this".to_string(), 0));
+                        return self.closure.borrow().get_at(Some(Slot{depth: 0, index: 0}), &Token::new(token::Type::This, "this".to_string(), 0));
                     }
-                    return Some(value)
+                    return Ok(value)
                 },
+                Err(UnwindType::Break) | Err(UnwindType::Continue) => panic!("A loop control statement escaped its function!"),
                 Ok(()) => {},
             }
         };
         if self.is_initializer {
             // This is a bit of a hack. Let's hope resolution dosen't magically fail, or the error
             // message will be strange!
-            self.closure.borrow().get_at(Some(0), &Token::new(token::Type::This, "this".to_string(), 0))
+            self.closure.borrow().get_at(Some(Slot{depth: 0, index: 0}), &Token::new(token::Type::This, "this".to_string(), 0))
         } else {
-            Some(Value::Nil)
+            Ok(Value::Nil)
         }
     }
+}
+
+impl Method for LoxCallable {
+    fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> Rc<dyn Callable> {
+        let mut environment = Environment::with_enclosing_scope(self.closure.clone());
+        environment.define("this", Value::Instance(instance));
+        gc::new_callable(LoxCallable::new(self.name.clone(), self.params.clone(), self.body.clone(), gc::new_environment(environment), self.is_initializer))
+    }
+}
+
+impl Callable for LoxCallable {
+    // Each call recurses on the native stack, so the depth is limited to keep deep recursion in Lox
+    // from overflowing it. The top level takes up the first frame, as it does in the VM.
+    fn call(self: Rc<Self>, arguments: Vec<Value>) -> Result<Value, Diagnostic> {
+        let depth = CALL_DEPTH.with(Cell::get);
+        if depth + 1 >= MAXIMUM_FRAME_COUNT {
+            return Err(Diagnostic::error(None, "Stack overflow."));
+        }
+        CALL_DEPTH.with(|call_depth| call_depth.set(depth + 1));
+        let result = self.run(arguments);
+        CALL_DEPTH.with(|call_depth| call_depth.set(depth));
+        result
+    }
+
+    fn arity(&self) -> Arity {
+        Arity::Exactly(self.params.len())
+    }
+
+    fn name(&self) -> &str {
+        self.name.lexeme()
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn frame_name(&self) -> Option<&str> {
+        Some(self.name.lexeme())
+    }
 
-    fn arity(&self) -> usize {
-        self.params.len()
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.pointer(&self.closure);
     }
 }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::expression::Value;
+use crate::token::Span;
+
+// Every instruction is a single opcode byte, followed by its operands. Constant, name and function
+// operands are two byte indexes into the pools below, jump offsets are two bytes, and local,
+// upvalue and argument counts are a single byte.
+#[derive(Debug, Clone, Copy)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    GetIndex,
+    SetIndex,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    CloseUpvalue,
+    Return,
+    Class,
+    Subclass,
+    List,
+    Map,
+    PushCatch,
+    PushFinally,
+    PopHandler,
+    Throw,
+    Rethrow,
+    Template,
+    Modulo,
+    Power,
+    IntegerDivide,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
+    BitwiseNot,
+}
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> OpCode {
+        match byte {
+            0 => OpCode::Constant,
+            1 => OpCode::Nil,
+            2 => OpCode::True,
+            3 => OpCode::False,
+            4 => OpCode::Pop,
+            5 => OpCode::GetLocal,
+            6 => OpCode::SetLocal,
+            7 => OpCode::GetGlobal,
+            8 => OpCode::DefineGlobal,
+            9 => OpCode::SetGlobal,
+            10 => OpCode::GetUpvalue,
+            11 => OpCode::SetUpvalue,
+            12 => OpCode::GetProperty,
+            13 => OpCode::SetProperty,
+            14 => OpCode::GetSuper,
+            15 => OpCode::GetIndex,
+            16 => OpCode::SetIndex,
+            17 => OpCode::Equal,
+            18 => OpCode::Greater,
+            19 => OpCode::GreaterEqual,
+            20 => OpCode::Less,
+            21 => OpCode::LessEqual,
+            22 => OpCode::Add,
+            23 => OpCode::Subtract,
+            24 => OpCode::Multiply,
+            25 => OpCode::Divide,
+            26 => OpCode::Not,
+            27 => OpCode::Negate,
+            28 => OpCode::Print,
+            29 => OpCode::Jump,
+            30 => OpCode::JumpIfFalse,
+            31 => OpCode::Loop,
+            32 => OpCode::Call,
+            33 => OpCode::Closure,
+            34 => OpCode::CloseUpvalue,
+            35 => OpCode::Return,
+            36 => OpCode::Class,
+            37 => OpCode::Subclass,
+            38 => OpCode::List,
+            39 => OpCode::Map,
+            40 => OpCode::PushCatch,
+            41 => OpCode::PushFinally,
+            42 => OpCode::PopHandler,
+            43 => OpCode::Throw,
+            44 => OpCode::Rethrow,
+            45 => OpCode::Template,
+            46 => OpCode::Modulo,
+            47 => OpCode::Power,
+            48 => OpCode::IntegerDivide,
+            49 => OpCode::BitwiseAnd,
+            50 => OpCode::BitwiseOr,
+            51 => OpCode::BitwiseXor,
+            52 => OpCode::ShiftLeft,
+            53 => OpCode::ShiftRight,
+            54 => OpCode::BitwiseNot,
+            _ => panic!("An invalid opcode snuck in!"),
+        }
+    }
+}
+
+// The compiled form of a function body, or of a whole script.
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    pub functions: Vec<Rc<Function>>,
+    // Run-length encoded; each entry is a span of source, and how many bytes of code came from it.
+    spans: Vec<(Span, usize)>,
+    // Where each string and number constant already is in the pool.
+    constant_indexes: HashMap<ConstantKey, usize>,
+}
+
+// Numbers are keyed by their bits, so that 0 and -0, which compare equal, don't share a slot.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    String(String),
+    Number(u64),
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            constants: Vec::new(),
+            functions: Vec::new(),
+            spans: Vec::new(),
+            constant_indexes: HashMap::new(),
+        }
+    }
+
+    pub fn write(&mut self, byte: u8, span: &Span) {
+        self.code.push(byte);
+        match self.spans.last_mut() {
+            Some((last_span, count)) if last_span == span => *count += 1,
+            _ => self.spans.push((span.clone(), 1)),
+        }
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: &Span) {
+        self.write(op as u8, span);
+    }
+
+    pub fn write_short(&mut self, value: u16, span: &Span) {
+        self.write((value >> 8) as u8, span);
+        self.write(value as u8, span);
+    }
+
+    pub fn read_short(&self, offset: usize) -> u16 {
+        (self.code[offset] as u16) << 8 | self.code[offset + 1] as u16
+    }
+
+    pub fn patch_short(&mut self, offset: usize, value: u16) {
+        self.code[offset] = (value >> 8) as u8;
+        self.code[offset + 1] = value as u8;
+    }
+
+    // Constants are deduplicated where possible, mostly so that names used over and over again
+    // don't fill up the pool.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = match &value {
+            Value::String(string) => Some(ConstantKey::String(string.clone())),
+            Value::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            _ => None,
+        };
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_indexes.get(key)) {
+            return *index;
+        }
+        self.constants.push(value);
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indexes.insert(key, index);
+        }
+        index
+    }
+
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.functions.push(Rc::new(function));
+        self.functions.len() - 1
+    }
+
+    pub fn span(&self, offset: usize) -> Span {
+        let mut seen = 0;
+        for (span, count) in &self.spans {
+            seen += count;
+            if offset < seen {
+                return span.clone();
+            }
+        }
+        self.spans.last().map(|(span, _)| span.clone()).unwrap_or_else(|| Span::on_line(0))
+    }
+}
@@ -0,0 +1,775 @@
+use crate::token;
+use crate::token::{Span, Token};
+use crate::chunk::{Chunk, Function, OpCode};
+use crate::expression::{Expression, Value};
+use crate::statement::{FunctionType, Statement};
+use crate::diagnostic::Diagnostic;
+
+const MAXIMUM_LOCAL_COUNT: usize = 256;
+
+struct Local {
+    name: String,
+    depth: usize,
+    is_captured: bool,
+}
+
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+struct Loop {
+    scope_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// A try statement we're in the middle of. Leaving it early with a return, break or continue has to
+// take its handler down, if it's still installed, and run its finally block on the way out.
+struct Try {
+    // How many loops were already open when it started.
+    loop_depth: usize,
+    has_handler: bool,
+    finally: Option<Vec<Statement>>,
+}
+
+// Everything we need to keep track of while compiling a single function.
+struct FunctionState {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    loops: Vec<Loop>,
+    tries: Vec<Try>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(name: &str, function_type: FunctionType) -> Self {
+        // Slot zero holds the function being called, or the receiver for methods.
+        let receiver = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        Self {
+            function: Function {
+                name: name.to_string(),
+                arity: 0,
+                upvalue_count: 0,
+                chunk: Chunk::new(),
+            },
+            function_type,
+            locals: vec![Local{name: receiver.to_string(), depth: 0, is_captured: false}],
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+            tries: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+// Compiles a resolved syntax tree into bytecode for the VM. The resolver has already rejected
+// programs with scoping errors, so the only errors reported here are limits of the bytecode format.
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    span: Span,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            states: vec![FunctionState::new("script", FunctionType::None)],
+            span: Span::on_line(0),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    // A script ending in an expression statement returns the value of that expression.
+    pub fn compile(mut self, statements: &[Statement]) -> Result<Function, Vec<Diagnostic>> {
+        match statements.split_last() {
+            Some((Statement::Expression{expression}, rest)) => {
+                for statement in rest {
+                    self.statement(statement);
+                }
+                self.expression(expression);
+                self.emit_op(OpCode::Return);
+            },
+            _ => {
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.emit_return();
+            },
+        }
+        if self.diagnostics.is_empty() {
+            Ok(self.states.pop().unwrap().function)
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        self.span = statement.span();
+        match statement {
+            Statement::Expression{expression} => {
+                self.expression(expression);
+                self.emit_op(OpCode::Pop);
+            },
+            Statement::Print{keyword: _, expression} => {
+                self.expression(expression);
+                self.emit_op(OpCode::Print);
+            },
+            Statement::Var{name, initializer} => {
+                self.span = name.span();
+                if let Some(init) = initializer {
+                    self.expression(init);
+                } else {
+                    self.emit_op(OpCode::Nil);
+                }
+                self.define_variable(name);
+            },
+            Statement::Block{brace: _, statements} => self.block(statements),
+            Statement::If{keyword: _, condition, then_branch, else_branch} => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_op(OpCode::Pop);
+                if let Some(branch) = else_branch {
+                    self.statement(branch);
+                }
+                self.patch_jump(else_jump);
+            },
+            Statement::While{keyword: _, condition, body, increment} => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                let scope_depth = self.state().scope_depth;
+                self.state_mut().loops.push(Loop{scope_depth, breaks: Vec::new(), continues: Vec::new()});
+                self.statement(body);
+                let current_loop = self.state_mut().loops.pop().unwrap();
+                for jump in current_loop.continues {
+                    self.patch_jump(jump);
+                }
+                if let Some(incr) = increment {
+                    self.expression(incr);
+                    self.emit_op(OpCode::Pop);
+                }
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit_op(OpCode::Pop);
+                for jump in current_loop.breaks {
+                    self.patch_jump(jump);
+                }
+            },
+            Statement::Break{keyword} => {
+                self.exit_tries_in_loop();
+                self.span = keyword.span();
+                self.discard_loop_locals();
+                let jump = self.emit_jump(OpCode::Jump);
+                self.state_mut().loops.last_mut().expect("'break' outside of a loop snuck past the resolver!").breaks.push(jump);
+            },
+            Statement::Continue{keyword} => {
+                self.exit_tries_in_loop();
+                self.span = keyword.span();
+                self.discard_loop_locals();
+                let jump = self.emit_jump(OpCode::Jump);
+                self.state_mut().loops.last_mut().expect("'continue' outside of a loop snuck past the resolver!").continues.push(jump);
+            },
+            Statement::Function{name, params, body} => {
+                self.span = name.span();
+                if self.state().scope_depth > 0 {
+                    // Declare the local first, so the function can refer to itself.
+                    self.add_local(name.lexeme());
+                    self.function(name, params, body, FunctionType::Function);
+                } else {
+                    self.function(name, params, body, FunctionType::Function);
+                    self.define_variable(name);
+                }
+            },
+            Statement::Return{keyword, value} => {
+                self.span = keyword.span();
+                if let Some(expr) = value {
+                    self.expression(expr);
+                } else {
+                    self.emit_default_return_value();
+                }
+                let tries = self.state().tries.len();
+                if tries > 0 {
+                    // The return value sits on the stack while the finally blocks run.
+                    self.begin_scope();
+                    self.add_local("");
+                    self.exit_tries(tries);
+                    self.forget_scope();
+                    self.span = keyword.span();
+                }
+                self.emit_op(OpCode::Return);
+            },
+            Statement::Class{name, superclass, methods} => self.class(name, superclass, methods),
+            Statement::Throw{keyword, value} => {
+                self.expression(value);
+                self.span = keyword.span();
+                self.emit_op(OpCode::Throw);
+            },
+            Statement::Try{keyword, body, catch, finally} => self.try_statement(keyword, body, catch, finally),
+            Statement::Import{keyword: _, path: _, names: _, bindings} => {
+                for (name, value) in bindings {
+                    self.emit_constant(value.clone());
+                    let constant = self.name_constant(name);
+                    self.emit_op(OpCode::DefineGlobal);
+                    self.emit_short(constant);
+                }
+            },
+        }
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        self.begin_scope();
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.end_scope();
+    }
+
+    // When a handler catches an error, everything above where the stack was when it was installed is
+    // thrown away, and the error is pushed in its place. Finally handlers push a placeholder instead,
+    // and the VM keeps the error to raise again once the finally block has run.
+    fn try_statement(&mut self, keyword: &Token, body: &[Statement], catch: &Option<(Token, Vec<Statement>)>, finally: &Option<Vec<Statement>>) {
+        let loop_depth = self.state().loops.len();
+        let handler = self.emit_jump(if catch.is_some() { OpCode::PushCatch } else { OpCode::PushFinally });
+        self.state_mut().tries.push(Try{loop_depth, has_handler: true, finally: finally.clone()});
+        self.block(body);
+        self.state_mut().tries.pop();
+        self.span = keyword.span();
+        self.emit_op(OpCode::PopHandler);
+        if let Some(finally) = finally {
+            self.block(finally);
+        }
+        let mut exit_jumps = vec![self.emit_jump(OpCode::Jump)];
+        self.patch_jump(handler);
+        match (catch, finally) {
+            (Some((name, handler_body)), finally) => {
+                self.begin_scope();
+                self.add_local(name.lexeme());
+                // Errors in the catch block still have to run the finally block.
+                let rethrow = finally.as_ref().map(|_| self.emit_jump(OpCode::PushFinally));
+                self.state_mut().tries.push(Try{loop_depth, has_handler: rethrow.is_some(), finally: finally.clone()});
+                for statement in handler_body {
+                    self.statement(statement);
+                }
+                self.state_mut().tries.pop();
+                self.span = keyword.span();
+                if rethrow.is_some() {
+                    self.emit_op(OpCode::PopHandler);
+                }
+                self.end_scope();
+                if let (Some(rethrow), Some(finally)) = (rethrow, finally) {
+                    self.block(finally);
+                    exit_jumps.push(self.emit_jump(OpCode::Jump));
+                    self.patch_jump(rethrow);
+                    self.finally_then_rethrow(2, finally);
+                }
+            },
+            (None, Some(finally)) => self.finally_then_rethrow(1, finally),
+            (None, None) => panic!("A try statement with nothing after it snuck in!"),
+        }
+        for jump in exit_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    // Where a finally handler lands. The stack holds the given number of slots above the locals
+    // that were there when the try statement started, the last being the placeholder for the error.
+    fn finally_then_rethrow(&mut self, slots: usize, finally: &[Statement]) {
+        self.begin_scope();
+        for _ in 0..slots {
+            self.add_local("");
+        }
+        self.block(finally);
+        self.emit_op(OpCode::Rethrow);
+        self.forget_scope();
+    }
+
+    // Leaves the innermost try statements early, taking down their handlers and running their finally
+    // blocks, innermost first.
+    fn exit_tries(&mut self, count: usize) {
+        let tries = self.state().tries.len();
+        for index in (tries - count..tries).rev() {
+            if self.state().tries[index].has_handler {
+                self.emit_op(OpCode::PopHandler);
+            }
+            if let Some(finally) = self.state().tries[index].finally.clone() {
+                // Leaving early from inside the finally block itself only leaves the tries around it.
+                let inner = self.state_mut().tries.split_off(index);
+                self.block(&finally);
+                self.state_mut().tries.extend(inner);
+            }
+        }
+    }
+
+    // A break or continue leaves the try statements started inside the innermost loop.
+    fn exit_tries_in_loop(&mut self) {
+        let loop_depth = self.state().loops.len();
+        let count = self.state().tries.iter().rev().take_while(|entry| entry.loop_depth >= loop_depth).count();
+        self.exit_tries(count);
+    }
+
+    fn class(&mut self, name: &Token, superclass: &Option<Expression>, methods: &[Statement]) {
+        self.span = name.span();
+        // The class is defined as nil first, so that methods can refer to it by name.
+        self.emit_op(OpCode::Nil);
+        if self.state().scope_depth > 0 {
+            self.add_local(name.lexeme());
+        } else {
+            self.define_variable(name);
+        }
+        let superclass_name = superclass.as_ref().map(|expr| {
+            self.begin_scope();
+            self.expression(expr);
+            self.add_local("super");
+            match expr {
+                Expression::Variable{name, slot: _} => name.clone(),
+                _ => panic!("An invalid superclass snuck in!"),
+            }
+        });
+        for method in methods {
+            match method {
+                Statement::Function{name: method_name, params, body} => {
+                    let function_type = if method_name.lexeme() == "init" {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    self.function(method_name, params, body, function_type);
+                },
+                _ => panic!("An invalid method snuck in!"),
+            }
+        }
+        self.span = name.span();
+        let name_constant = self.name_constant(name.lexeme());
+        if let Some(superclass_name) = superclass_name {
+            let superclass_constant = self.name_constant(superclass_name.lexeme());
+            // Checking the superclass is a class is what can fail here, so that's where errors point.
+            self.span = superclass_name.span();
+            self.emit_op(OpCode::Subclass);
+            self.emit_short(name_constant);
+            self.emit_short(superclass_constant);
+        } else {
+            self.emit_op(OpCode::Class);
+            self.emit_short(name_constant);
+        }
+        if methods.len() > u16::MAX as usize {
+            self.error("Too many methods in one class.");
+        }
+        self.emit_short(methods.len() as u16);
+        self.set_variable(name);
+        self.emit_op(OpCode::Pop);
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    fn function(&mut self, name: &Token, params: &[Token], body: &[Statement], function_type: FunctionType) {
+        self.states.push(FunctionState::new(name.lexeme(), function_type));
+        self.begin_scope();
+        self.state_mut().function.arity = params.len();
+        for param in params {
+            self.add_local(param.lexeme());
+        }
+        for statement in body {
+            self.statement(statement);
+        }
+        self.emit_return();
+        let state = self.states.pop().unwrap();
+        let mut function = state.function;
+        function.upvalue_count = state.upvalues.len();
+        let index = self.chunk().add_function(function);
+        self.span = name.span();
+        if index > u16::MAX as usize {
+            self.error("Too many functions in one chunk.");
+        }
+        self.emit_op(OpCode::Closure);
+        self.emit_short(index as u16);
+        for upvalue in state.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Literal{token, value} => {
+                self.span = token.span();
+                match value {
+                    Value::Nil => self.emit_op(OpCode::Nil),
+                    Value::True => self.emit_op(OpCode::True),
+                    Value::False => self.emit_op(OpCode::False),
+                    _ => self.emit_constant(value.clone()),
+                }
+            },
+            Expression::Grouping{paren: _, expression} => self.expression(expression),
+            Expression::Unary{operator, right} => {
+                self.expression(right);
+                self.span = operator.span();
+                match operator.token_type() {
+                    token::Type::Minus => self.emit_op(OpCode::Negate),
+                    token::Type::Bang => self.emit_op(OpCode::Not),
+                    token::Type::Tilde => self.emit_op(OpCode::BitwiseNot),
+                    _ => panic!("An invalid unary operator snuck in!"),
+                }
+            },
+            Expression::Binary{left, operator, right} => {
+                self.expression(left);
+                self.expression(right);
+                self.span = operator.span();
+                match operator.token_type() {
+                    token::Type::Greater => self.emit_op(OpCode::Greater),
+                    token::Type::GreaterEqual => self.emit_op(OpCode::GreaterEqual),
+                    token::Type::Less => self.emit_op(OpCode::Less),
+                    token::Type::LessEqual => self.emit_op(OpCode::LessEqual),
+                    token::Type::BangEqual => {
+                        self.emit_op(OpCode::Equal);
+                        self.emit_op(OpCode::Not);
+                    },
+                    token::Type::EqualEqual => self.emit_op(OpCode::Equal),
+                    token::Type::Minus => self.emit_op(OpCode::Subtract),
+                    token::Type::Plus => self.emit_op(OpCode::Add),
+                    token::Type::Slash => self.emit_op(OpCode::Divide),
+                    token::Type::Star => self.emit_op(OpCode::Multiply),
+                    token::Type::Percent => self.emit_op(OpCode::Modulo),
+                    token::Type::StarStar => self.emit_op(OpCode::Power),
+                    token::Type::TildeSlash => self.emit_op(OpCode::IntegerDivide),
+                    token::Type::Ampersand => self.emit_op(OpCode::BitwiseAnd),
+                    token::Type::Pipe => self.emit_op(OpCode::BitwiseOr),
+                    token::Type::Caret => self.emit_op(OpCode::BitwiseXor),
+                    token::Type::LessLess => self.emit_op(OpCode::ShiftLeft),
+                    token::Type::GreaterGreater => self.emit_op(OpCode::ShiftRight),
+                    _ => panic!("An invalid binary operator snuck in!"),
+                }
+            },
+            Expression::Logical{left, operator, right} => {
+                self.expression(left);
+                self.span = operator.span();
+                match operator.token_type() {
+                    token::Type::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    },
+                    token::Type::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    },
+                    _ => panic!("An invalid logical operator snuck in!"),
+                }
+            },
+            Expression::Variable{name, slot: _} => self.get_variable(name),
+            Expression::This{keyword, slot: _} => self.get_variable(keyword),
+            Expression::Assignment{name, value, slot: _} => {
+                self.expression(value);
+                self.set_variable(name);
+            },
+            Expression::Call{callee, paren, arguments} => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.span = paren.span();
+                self.emit_op(OpCode::Call);
+                self.emit_byte(arguments.len() as u8);
+            },
+            Expression::Get{object, name} => {
+                self.expression(object);
+                self.span = name.span();
+                let constant = self.name_constant(name.lexeme());
+                self.emit_op(OpCode::GetProperty);
+                self.emit_short(constant);
+            },
+            Expression::Set{object, name, value} => {
+                self.expression(object);
+                self.expression(value);
+                self.span = name.span();
+                let constant = self.name_constant(name.lexeme());
+                self.emit_op(OpCode::SetProperty);
+                self.emit_short(constant);
+            },
+            Expression::Super{keyword, method, slot: _} => {
+                self.span = keyword.span();
+                self.get_variable(&Token::with_span(token::Type::This, "this".to_string(), keyword.span()));
+                self.get_variable(keyword);
+                let constant = self.name_constant(method.lexeme());
+                self.emit_op(OpCode::GetSuper);
+                self.emit_short(constant);
+            },
+            Expression::List{bracket, elements} => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.span = bracket.span();
+                self.emit_op(OpCode::List);
+                self.emit_count(elements.len());
+            },
+            Expression::Template{quote, parts} => {
+                for part in parts {
+                    self.expression(part);
+                }
+                self.span = quote.span();
+                self.emit_op(OpCode::Template);
+                self.emit_count(parts.len());
+            },
+            Expression::Map{brace, entries} => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.span = brace.span();
+                self.emit_op(OpCode::Map);
+                self.emit_count(entries.len());
+            },
+            Expression::GetIndex{object, bracket, index} => {
+                self.expression(object);
+                self.expression(index);
+                self.span = bracket.span();
+                self.emit_op(OpCode::GetIndex);
+            },
+            Expression::SetIndex{object, bracket, index, value} => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+                self.span = bracket.span();
+                self.emit_op(OpCode::SetIndex);
+            },
+            Expression::Lambda{keyword, params, body} => {
+                // Anonymous functions are the ones with an empty name.
+                let name = Token::with_span(token::Type::Fun, String::new(), keyword.span());
+                self.function(&name, params, body, FunctionType::Function);
+            },
+        }
+    }
+
+    fn get_variable(&mut self, name: &Token) {
+        self.span = name.span();
+        let top = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(top, name.lexeme()) {
+            self.emit_op(OpCode::GetLocal);
+            self.emit_byte(slot);
+        } else if let Some(index) = self.resolve_upvalue(top, name.lexeme()) {
+            self.emit_op(OpCode::GetUpvalue);
+            self.emit_byte(index);
+        } else {
+            let constant = self.name_constant(name.lexeme());
+            self.emit_op(OpCode::GetGlobal);
+            self.emit_short(constant);
+        }
+    }
+
+    fn set_variable(&mut self, name: &Token) {
+        self.span = name.span();
+        let top = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(top, name.lexeme()) {
+            self.emit_op(OpCode::SetLocal);
+            self.emit_byte(slot);
+        } else if let Some(index) = self.resolve_upvalue(top, name.lexeme()) {
+            self.emit_op(OpCode::SetUpvalue);
+            self.emit_byte(index);
+        } else {
+            let constant = self.name_constant(name.lexeme());
+            self.emit_op(OpCode::SetGlobal);
+            self.emit_short(constant);
+        }
+    }
+
+    // The value of the variable is on top of the stack. Locals just stay there, globals are moved
+    // into the globals table.
+    fn define_variable(&mut self, name: &Token) {
+        if self.state().scope_depth > 0 {
+            self.add_local(name.lexeme());
+        } else {
+            let constant = self.name_constant(name.lexeme());
+            self.emit_op(OpCode::DefineGlobal);
+            self.emit_short(constant);
+        }
+    }
+
+    fn add_local(&mut self, name: &str) {
+        if self.state().locals.len() >= MAXIMUM_LOCAL_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        let depth = self.state().scope_depth;
+        self.state_mut().locals.push(Local{name: name.to_string(), depth, is_captured: false});
+    }
+
+    fn resolve_local(&self, state: usize, name: &str) -> Option<u8> {
+        self.states[state].locals.iter().rposition(|local| local.name == name).map(|slot| slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(state, slot, true));
+        }
+        let index = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, index, false))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|upvalue| upvalue.index == index && upvalue.is_local == is_local) {
+            return existing as u8;
+        }
+        if upvalues.len() >= MAXIMUM_LOCAL_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        self.states[state].upvalues.push(Upvalue{index, is_local});
+        (self.states[state].upvalues.len() - 1) as u8
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        let depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth <= depth {
+                break;
+            }
+            let op = if local.is_captured { OpCode::CloseUpvalue } else { OpCode::Pop };
+            self.emit_op(op);
+            self.state_mut().locals.pop();
+        }
+    }
+
+    // Pops the locals declared inside the innermost loop, without forgetting about them; the code
+    // after a 'break' or 'continue' still needs them.
+    fn discard_loop_locals(&mut self) {
+        let loop_depth = self.state().loops.last().map(|l| l.scope_depth).unwrap_or(0);
+        let ops: Vec<OpCode> = self.state().locals.iter().rev()
+            .take_while(|local| local.depth > loop_depth)
+            .map(|local| if local.is_captured { OpCode::CloseUpvalue } else { OpCode::Pop })
+            .collect();
+        for op in ops {
+            self.emit_op(op);
+        }
+    }
+
+    // Ends a scope whose locals are already off the stack, or that can't be fallen out of.
+    fn forget_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        let depth = self.state().scope_depth;
+        while self.state().locals.last().is_some_and(|local| local.depth > depth) {
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn emit_return(&mut self) {
+        self.emit_default_return_value();
+        self.emit_op(OpCode::Return);
+    }
+
+    fn emit_default_return_value(&mut self) {
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_op(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_op(OpCode::Constant);
+        self.emit_short(constant);
+    }
+
+    fn name_constant(&mut self, name: &str) -> u16 {
+        self.make_constant(Value::String(name.to_string()))
+    }
+
+    fn make_constant(&mut self, value: Value) -> u16 {
+        let constant = self.chunk().add_constant(value);
+        if constant > u16::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            0
+        } else {
+            constant as u16
+        }
+    }
+
+    fn emit_count(&mut self, count: usize) {
+        if count > u16::MAX as usize {
+            self.error("Too many elements in literal.");
+        }
+        self.emit_short(count as u16);
+    }
+
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_short(u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // Jump over the operand as well.
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        self.chunk().patch_short(offset, jump as u16);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.emit_short(offset as u16);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        let span = self.span.clone();
+        self.chunk().write_op(op, &span);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let span = self.span.clone();
+        self.chunk().write(byte, &span);
+    }
+
+    fn emit_short(&mut self, value: u16) {
+        let span = self.span.clone();
+        self.chunk().write_short(value, &span);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.diagnostics.push(Diagnostic::error(Some(self.span.clone()), message));
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+}
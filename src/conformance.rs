@@ -0,0 +1,210 @@
+// Runs Lox scripts annotated the way the Crafting Interpreters test suite is, and checks that the
+// interpreter did what the annotations say. Scripts are run by a separate interpreter process, since
+// print statements write straight to stdout.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+
+// Directories of the upstream suite that can't be run as they are. The first chapters' tests are
+// for interpreters that stop partway through the book, and the limits are clox's.
+const SKIPPED_DIRECTORIES: &[(&str, &str)] = &[
+    ("benchmark", "benchmarks aren't tests"),
+    ("expressions", "expects an interpreter that only evaluates expressions"),
+    ("scanning", "expects an interpreter that only prints tokens"),
+    ("limit", "tests clox's limits"),
+];
+
+// What a script's comments say should happen when it's run.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    // Each line of output, and the line of the script that expects it.
+    pub output: Vec<(String, usize)>,
+    // Compile errors, as the first line of each diagnostic, e.g. "[line 3] Error at 'x': ...".
+    pub compile_errors: Vec<String>,
+    // The message of the runtime error the script ends with, and the line it happens on.
+    pub runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    // Picks out these comments:
+    //
+    //   // expect: <a line of output>
+    //   // expect runtime error: <message>
+    //   // Error at 'x': <message>            (a compile error on the same line)
+    //   // [line N] Error at 'x': <message>   (a compile error on line N)
+    //
+    // Upstream tests can mark errors as only for jlox with "[java line N]", which our messages
+    // follow; the ones for clox, marked "[c line N]", are ignored.
+    pub fn parse(source: &str) -> Self {
+        let mut expectations = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let number = index + 1;
+            let comment = match line.find("// ") {
+                Some(start) => &line[start + 3..],
+                None => continue,
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push((output.to_string(), number));
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_string(), number));
+            } else if comment.starts_with("Error") {
+                expectations.compile_errors.push(format!("[line {}] {}", number, comment));
+            } else if let Some(rest) = comment.strip_prefix("[line ").or_else(|| comment.strip_prefix("[java line ")) {
+                if rest.split("] ").nth(1).is_some_and(|error| error.starts_with("Error")) {
+                    expectations.compile_errors.push(format!("[line {}", rest));
+                }
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            EX_DATAERR
+        } else if self.runtime_error.is_some() {
+            EX_SOFTWARE
+        } else {
+            0
+        }
+    }
+
+    // Everything about how a run went that doesn't match, or nothing if the script passed.
+    pub fn check(&self, stdout: &str, stderr: &str, exit_code: Option<i32>) -> Vec<String> {
+        let mut failures = Vec::new();
+        // Diagnostics start with their location. The snippets, stack traces and notes after them
+        // are left alone.
+        let headers: Vec<&str> = stderr.lines().filter(|line| line.starts_with("[line ")).collect();
+
+        if !self.compile_errors.is_empty() {
+            for expected in &self.compile_errors {
+                if !headers.contains(&expected.as_str()) {
+                    failures.push(format!("Missing expected error: {}", expected));
+                }
+            }
+            for header in &headers {
+                if !self.compile_errors.iter().any(|expected| expected == header) {
+                    failures.push(format!("Unexpected error: {}", header));
+                }
+            }
+        } else if let Some((message, line)) = &self.runtime_error {
+            match headers.first() {
+                Some(header) if header.starts_with(&format!("[line {}] Error", line)) && header.ends_with(&format!(": {}", message)) => {},
+                Some(header) => failures.push(format!("Expected runtime error '{}' on line {} and got: {}", message, line, header)),
+                None => failures.push(format!("Expected runtime error '{}' on line {} and got none.", message, line)),
+            }
+        } else if let Some(header) = headers.first() {
+            failures.push(format!("Unexpected error: {}", header));
+        }
+
+        let mut actual = stdout.lines();
+        for (expected, line) in &self.output {
+            match actual.next() {
+                Some(output) if output == expected => {},
+                Some(output) => failures.push(format!("Expected output '{}' on line {} and got '{}'.", expected, line, output)),
+                None => failures.push(format!("Missing expected output '{}' on line {}.", expected, line)),
+            }
+        }
+        for output in actual {
+            failures.push(format!("Got output '{}' when none was expected.", output));
+        }
+
+        match exit_code {
+            Some(code) if code == self.exit_code() => {},
+            Some(code) => failures.push(format!("Expected exit code {} and got {}.", self.exit_code(), code)),
+            None => failures.push("The interpreter was killed by a signal.".to_string()),
+        }
+        failures
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(Vec<String>),
+    Skip(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub passed: usize,
+    pub skipped: usize,
+    // Each failing script, with what went wrong.
+    pub failures: Vec<(PathBuf, Vec<String>)>,
+}
+
+impl Summary {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+// Runs scripts with an interpreter executable, like the lox-rs binary, passing it the given
+// arguments before each script.
+pub struct Runner {
+    interpreter: PathBuf,
+    arguments: Vec<String>,
+}
+
+impl Runner {
+    pub fn new(interpreter: PathBuf, arguments: Vec<String>) -> Self {
+        Self {
+            interpreter,
+            arguments,
+        }
+    }
+
+    pub fn run_file(&self, path: &Path) -> io::Result<Outcome> {
+        let source = fs::read_to_string(path)?;
+        let expectations = Expectations::parse(&source);
+        let output = Command::new(&self.interpreter).args(&self.arguments).arg(path).output()?;
+        let failures = expectations.check(&String::from_utf8_lossy(&output.stdout), &String::from_utf8_lossy(&output.stderr), output.status.code());
+        Ok(if failures.is_empty() { Outcome::Pass } else { Outcome::Fail(failures) })
+    }
+
+    // Runs every .lox file under the directory, in order of their paths. Calls back with each
+    // outcome as it's known, so that progress can be shown.
+    pub fn run_directory<F>(&self, directory: &Path, mut report: F) -> io::Result<Summary>
+    where
+        F: FnMut(&Path, &Outcome),
+    {
+        let mut summary = Summary::default();
+        for path in lox_files(directory)? {
+            let outcome = match skip_reason(directory, &path) {
+                Some(reason) => Outcome::Skip(reason.to_string()),
+                None => self.run_file(&path)?,
+            };
+            report(&path, &outcome);
+            match outcome {
+                Outcome::Pass => summary.passed += 1,
+                Outcome::Skip(_) => summary.skipped += 1,
+                Outcome::Fail(failures) => summary.failures.push((path, failures)),
+            }
+        }
+        Ok(summary)
+    }
+}
+
+fn skip_reason(directory: &Path, path: &Path) -> Option<&'static str> {
+    let relative = path.strip_prefix(directory).unwrap_or(path);
+    let top = relative.components().next()?.as_os_str().to_str()?;
+    SKIPPED_DIRECTORIES.iter().find(|(skipped, _)| *skipped == top).map(|(_, reason)| *reason)
+}
+
+fn lox_files(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(lox_files(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,337 @@
+use std::{collections::HashMap, error, fmt, mem, ptr, rc::Rc};
+use std::ops::{Deref, DerefMut};
+
+use crate::token;
+use crate::token::{Span, Token};
+use crate::expression::Value;
+use crate::lox_class::{LoxClass, LoxInstance};
+use crate::gc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// Something found to be wrong with a program, either before or while running it. Nothing is
+// printed by the interpreter itself; diagnostics are handed back to whoever ran the code.
+//
+// The details are boxed, and reached through Deref, so that results carrying a diagnostic are only
+// a pointer wide. The tree-walker passes them back through every level of a Lox call, and a large
+// error type would take up native stack on each one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic(Box<Details>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Details {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    // The text of the offending token, if the diagnostic is about one. Empty at the end of the
+    // input.
+    pub lexeme: Option<String>,
+    // The calls that were in progress when a runtime error happened, outermost first. Empty if it
+    // happened at the top level.
+    pub stack_trace: Vec<StackFrame>,
+    // What a `throw` statement threw, so that whatever catches it gets it back unchanged.
+    pub value: Option<Value>,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    // None for the top level of the script.
+    pub function: Option<String>,
+    // Which source the line is in, as in Span.
+    pub source: Option<Rc<Source>>,
+    // The line that was running in this function: the call to the next frame in, or the error.
+    pub line: usize,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(function) if function.is_empty() => write!(f, "[line {}] in anonymous function", self.line),
+            Some(function) => write!(f, "[line {}] in {}()", self.line, function),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+impl Deref for Diagnostic {
+    type Target = Details;
+
+    fn deref(&self) -> &Details {
+        &self.0
+    }
+}
+
+impl DerefMut for Diagnostic {
+    fn deref_mut(&mut self) -> &mut Details {
+        &mut self.0
+    }
+}
+
+impl Diagnostic {
+    pub fn error(span: Option<Span>, message: &str) -> Self {
+        Self(Box::new(Details {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span,
+            lexeme: None,
+            stack_trace: Vec::new(),
+            value: None,
+            notes: Vec::new(),
+        }))
+    }
+
+    pub fn at(span: Span, lexeme: &str, message: &str) -> Self {
+        let mut diagnostic = Self::error(Some(span), message);
+        diagnostic.lexeme = Some(lexeme.to_string());
+        diagnostic
+    }
+
+    pub fn on_token(token: &Token, message: &str) -> Self {
+        if mem::discriminant(token.token_type()) == mem::discriminant(&token::Type::EOF) {
+            Self::at(token.span(), "", message)
+        } else {
+            Self::at(token.span(), token.lexeme(), message)
+        }
+    }
+
+    // Instances with a message field, like the errors caught from built-in operations, are thrown
+    // with that as their message.
+    pub(crate) fn thrown(span: Span, value: Value) -> Self {
+        let message = match &value {
+            Value::Instance(instance) => match instance.borrow().field("message") {
+                Some(Value::String(message)) => message,
+                _ => value.to_string(),
+            },
+            _ => value.to_string(),
+        };
+        let mut diagnostic = Self::at(span, "throw", &message);
+        diagnostic.value = Some(value);
+        diagnostic
+    }
+
+    // What a catch block gets: the thrown value, or an Error instance holding the message and line.
+    pub(crate) fn into_value(self) -> Value {
+        let details = *self.0;
+        if let Some(value) = details.value {
+            return value;
+        }
+        let mut error = LoxInstance::new(gc::new_callable(LoxClass::new("Error".to_string(), None, HashMap::new())));
+        error.set_field("message", Value::String(details.message));
+        error.set_field("line", details.span.map_or(Value::Nil, |span| Value::Number(span.line as f64)));
+        Value::Instance(gc::new_instance(error))
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    // Called as an error unwinds out of a call to a function, made at the given span. The outermost
+    // frame is always left as the top level, which it is until we find out otherwise.
+    pub fn unwound_from(mut self, function: &str, call: Span) -> Self {
+        match self.stack_trace.first_mut() {
+            Some(caller) => caller.function = Some(function.to_string()),
+            None => {
+                let error = self.span.clone().unwrap_or_else(|| call.clone());
+                self.stack_trace.push(StackFrame{function: Some(function.to_string()), source: error.source, line: error.line});
+            },
+        }
+        self.stack_trace.insert(0, StackFrame{function: None, source: call.source, line: call.line});
+        self
+    }
+
+    // Errors from natives don't know where they happened, so they're put on the call.
+    pub fn or_at(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    // The header line, then the offending line of source with the span underlined, like:
+    //
+    // [line 1] Error at '+': Operands must be either two numbers or two strings.
+    //   --> script.lox:1:9
+    //    |
+    //  1 | print 1 + "one";
+    //    |         ^
+    pub fn render(&self) -> String {
+        let mut rendered = self.header();
+        if let Some((span, Source{name: file, text: source})) = self.span.as_ref().and_then(|span| Some((span, span.source.as_deref()?))) {
+            if let Some(line) = source.lines().nth(span.line.saturating_sub(1)) {
+                let gutter = " ".repeat(span.line.to_string().len());
+                if span.column == 0 {
+                    rendered += &format!("\n{} --> {}:{}", gutter, file, span.line);
+                } else {
+                    rendered += &format!("\n{} --> {}:{}:{}", gutter, file, span.line, span.column);
+                }
+                rendered += &format!("\n{} |\n{} | {}", gutter, span.line, line);
+                if span.column > 0 {
+                    // Keep tabs, so the underline lines up with the text above it.
+                    let indent: String = line.chars().take(span.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                    let text = source.get(span.offset..span.offset + span.length).unwrap_or("");
+                    let width = text.lines().next().unwrap_or("").chars().count().max(1);
+                    rendered += &format!("\n{} | {}{}", gutter, indent, "^".repeat(width));
+                }
+            }
+        }
+        rendered += &self.footer(true);
+        rendered
+    }
+
+    // The stack trace, innermost call last, and then any notes. Runs of the same frame, as in deep
+    // recursion, are only shown once. Frames are labelled with their file if the calls went through
+    // more than one, and they're wanted.
+    fn footer(&self, with_files: bool) -> String {
+        let mut footer = String::new();
+        if !self.stack_trace.is_empty() {
+            footer += "\nStack trace (most recent call last):";
+            let show_files = with_files && self.stack_trace.iter().any(|frame| frame.source != self.stack_trace[0].source);
+            let mut frames = self.stack_trace.iter().peekable();
+            while let Some(frame) = frames.next() {
+                footer += &format!("\n  {}", frame);
+                if let Some(source) = frame.source.as_ref().filter(|_| show_files) {
+                    footer += &format!(" ({})", source.name);
+                }
+                let mut repeats = 0;
+                while frames.next_if_eq(&frame).is_some() {
+                    repeats += 1;
+                }
+                if repeats > 0 {
+                    footer += &format!("\n  ... repeated {} more times", repeats);
+                }
+            }
+        }
+        for note in &self.notes {
+            footer += &format!("\n{}", note);
+        }
+        footer
+    }
+
+    fn header(&self) -> String {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        match (&self.span, &self.lexeme) {
+            (Some(span), None) => format!("[line {}] {}: {}", span.line, severity, self.message),
+            (Some(span), Some(lexeme)) if lexeme.is_empty() => format!("[line {}] {} at end: {}", span.line, severity, self.message),
+            (Some(span), Some(lexeme)) => format!("[line {}] {} at '{}': {}", span.line, severity, lexeme, self.message),
+            (None, _) => format!("{}: {}", severity, self.message),
+        }
+    }
+}
+
+// A piece of source that has been run, kept so that diagnostics can quote it. Every span into it
+// holds on to it, so it's dropped once nothing can refer to it any more, and no sooner.
+pub struct Source {
+    // The path of the file, or something like "<repl>" if it didn't come from one.
+    pub name: String,
+    pub text: String,
+}
+
+// Sources are compared by identity: running the same text twice still gives two sources.
+impl PartialEq for Source {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+// Only the name is shown, since the text can be a whole file.
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Source({:?})", self.name)
+    }
+}
+
+// The format the command line tool prints, e.g. "[line 1] Error at 'x': Undefined variable 'x'.",
+// followed by any stack trace and notes on lines of their own.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.header(), self.footer(false))
+    }
+}
+
+// Why a piece of source couldn't be run to completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    // Scanning, parsing or resolution failed, so nothing was run.
+    Compile(Vec<Diagnostic>),
+    Runtime(Diagnostic),
+}
+
+impl LoxError {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            LoxError::Compile(diagnostics) => diagnostics,
+            LoxError::Runtime(diagnostic) => std::slice::from_ref(diagnostic),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let rendered: Vec<String> = self.diagnostics().iter().map(Diagnostic::render).collect();
+        rendered.join("\n")
+    }
+
+    // Adds the note to every diagnostic.
+    pub fn with_note(self, note: String) -> Self {
+        match self {
+            LoxError::Compile(diagnostics) => LoxError::Compile(diagnostics.into_iter().map(|diagnostic| diagnostic.with_note(note.clone())).collect()),
+            LoxError::Runtime(diagnostic) => LoxError::Runtime(diagnostic.with_note(note)),
+        }
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let messages: Vec<String> = self.diagnostics().iter().map(|diagnostic| diagnostic.to_string()).collect();
+        write!(f, "{}", messages.join("\n"))
+    }
+}
+
+impl error::Error for LoxError {}
+
+// An error raised by a native function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    message: String,
+}
+
+impl RuntimeError {
+    pub fn new(message: &str) -> Self {
+        Self {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for RuntimeError {}
+
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+        }
+    }
+}
+
+impl From<&str> for RuntimeError {
+    fn from(message: &str) -> Self {
+        Self::new(message)
+    }
+}
@@ -1,102 +1,171 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::token::Token;
 use crate::expression::Value;
-use crate::error_reporter::ERROR_REPORTER;
+use crate::gc::{Trace, Tracer};
+use crate::diagnostic::Diagnostic;
+
+// Where the resolver found a local: how many environments out from the current one, and its index
+// within that environment.
+#[derive(Clone, Copy)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+// Shown after resolved variables when dumping the syntax tree, e.g. `(variable x @1:0)`.
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}:{}", self.depth, self.index)
+    }
+}
+
+// The resolver's view of an environment. Locals are handed out slots in the order they're
+// declared, which is also the order the interpreter defines them in.
+pub struct Scope {
+    // Whether each variable has finished being defined, and its slot.
+    variables: HashMap<String, (bool, usize)>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self {
+            variables: HashMap::new(),
+        }
+    }
+
+    pub fn declare(&mut self, name: &Token, diagnostics: &mut Vec<Diagnostic>) {
+        if self.variables.contains_key(name.lexeme()) {
+            diagnostics.push(Diagnostic::on_token(name, "A variable with this name already exists in this scope."));
+            return;
+        }
+        let index = self.variables.len();
+        self.variables.insert(name.lexeme().to_string(), (false, index));
+    }
+
+    pub fn define(&mut self, name: &str) {
+        let index = self.variables.len();
+        self.variables.entry(name.to_string()).or_insert((false, index)).0 = true;
+    }
+
+    pub fn is_declared_but_undefined(&self, name: &str) -> bool {
+        matches!(self.variables.get(name), Some((false, _)))
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.variables.get(name).map(|(_, index)| *index)
+    }
+}
+
+// Finds the innermost scope declaring a name. Anything not found is assumed to be global.
+pub fn resolve_local(scopes: &[Scope], name: &str) -> Option<Slot> {
+    scopes.iter().rev().enumerate().find_map(|(depth, scope)| scope.index_of(name).map(|index| Slot{depth, index}))
+}
 
 pub struct Environment {
-    values: HashMap<String, Value>,
+    // Locals, indexed by the slots the resolver handed out.
+    values: Vec<Value>,
+    // Only the outermost environment holds globals. They're still looked up by name, since they can
+    // be defined after the code using them has been resolved.
+    globals: HashMap<String, Value>,
     enclosing: Option<Rc<RefCell<Environment>>>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
-            values: HashMap::new(),
+            values: Vec::new(),
+            globals: HashMap::new(),
             enclosing: None,
         }
     }
 
     pub fn with_enclosing_scope(enclosing: Rc<RefCell<Environment>>) -> Self {
         Self {
-            values: HashMap::new(),
+            values: Vec::new(),
+            globals: HashMap::new(),
             enclosing: Some(enclosing),
         }
     }
 
-    pub fn define(&mut self, name: String, value: Value) {
-        self.values.insert(name, value);
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.globals.clear();
+        self.enclosing = None;
     }
 
-    pub fn get(&self, name: &Token) -> Option<Value> {
-        if let Some(value) = self.values.get(name.lexeme()) {
-            return Some(value.clone());
-        }
-
-        if let Some(enclosing) = &self.enclosing {
-            if let Some(value) = enclosing.borrow().get(name) {
-                return Some(value.clone());
-            }
+    pub fn define(&mut self, name: &str, value: Value) {
+        if self.enclosing.is_some() {
+            self.values.push(value);
+        } else {
+            self.globals.insert(name.to_string(), value);
         }
-
-        ERROR_REPORTER.lock().unwrap().runtime_error_on_token(name, &format!("Undefined variable '{}'.", name.lexeme()));
-        None
     }
 
-    pub fn assign(&mut self, name: Token, value: Value) -> Option<()> {
-        if self.values.contains_key(name.lexeme()) {
-            self.values.insert(name.lexeme().to_string(), value);
-            return Some(());
-        }
-
-        if let Some(enclosing) = &mut self.enclosing {
-            return enclosing.borrow_mut().assign(name, value);
+    pub fn get_at(&self, slot: Option<Slot>, name: &Token) -> Result<Value, Diagnostic> {
+        match slot {
+            Some(Slot{depth: 0, index}) => Ok(self.values[index].clone()),
+            Some(Slot{depth, index}) => self.enclosing.as_ref().unwrap().borrow().get_at(Some(Slot{depth: depth - 1, index}), name),
+            None => self.get_global(name),
         }
-
-        ERROR_REPORTER.lock().unwrap().runtime_error_on_token(&name, &format!("Undefined variable '{}'.", name.lexeme()));
-        None
     }
 
-    pub fn get_at(&self, distance: Option<usize>, name: &Token) -> Option<Value> {
-        if let Some(dist) = distance {
-            if dist == 0 {
-                self.get(name)
-            } else {
-                self.enclosing.as_ref().unwrap().borrow().get_at(Some(dist - 1), name)
-            }
+    pub fn global(&self, name: &str) -> Option<Value> {
+        if let Some(encl) = &self.enclosing {
+            encl.borrow().global(name)
         } else {
-            self.get_global(name)
+            self.globals.get(name).cloned()
         }
     }
 
-    fn get_global(&self, name: &Token) -> Option<Value> {
+    // Every global, in no particular order.
+    pub fn globals(&self) -> Vec<(String, Value)> {
         if let Some(encl) = &self.enclosing {
-            encl.borrow().get_global(name)
+            encl.borrow().globals()
         } else {
-            self.get(name)
+            self.globals.iter().map(|(name, value)| (name.clone(), value.clone())).collect()
         }
     }
 
-    pub fn assign_at(&mut self, distance: Option<usize>, name: Token, value: Value) -> Option<()> {
-        if let Some(dist) = distance {
-            if dist == 0 {
-                self.assign(name, value)
-            } else {
-                self.enclosing.as_ref().unwrap().borrow_mut().assign_at(Some(dist - 1), name, value)
-            }
-        } else {
-            self.assign_global(name, value)
-        }
+    fn get_global(&self, name: &Token) -> Result<Value, Diagnostic> {
+        self.global(name.lexeme()).ok_or_else(|| Diagnostic::on_token(name, &format!("Undefined variable '{}'.", name.lexeme())))
     }
 
+    pub fn assign_at(&mut self, slot: Option<Slot>, name: &Token, value: Value) -> Result<(), Diagnostic> {
+        match slot {
+            Some(Slot{depth: 0, index}) => {
+                self.values[index] = value;
+                Ok(())
+            },
+            Some(Slot{depth, index}) => self.enclosing.as_ref().unwrap().borrow_mut().assign_at(Some(Slot{depth: depth - 1, index}), name, value),
+            None => self.assign_global(name, value),
+        }
+    }
 
-    pub fn assign_global(&mut self, name: Token, value: Value) -> Option<()> {
+    fn assign_global(&mut self, name: &Token, value: Value) -> Result<(), Diagnostic> {
         if let Some(encl) = &mut self.enclosing {
-            encl.borrow_mut().assign_global(name, value)
-        } else {
-            self.assign(name, value)
+            return encl.borrow_mut().assign_global(name, value);
+        }
+
+        if let Some(global) = self.globals.get_mut(name.lexeme()) {
+            *global = value;
+            return Ok(());
+        }
+
+        Err(Diagnostic::on_token(name, &format!("Undefined variable '{}'.", name.lexeme())))
+    }
+}
+
+impl Trace for Environment {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values.iter().chain(self.globals.values()) {
+            value.trace(tracer);
+        }
+        if let Some(enclosing) = &self.enclosing {
+            tracer.pointer(enclosing);
         }
     }
 }
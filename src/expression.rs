@@ -1,12 +1,21 @@
-use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+use std::{cell::RefCell, fmt, rc::Rc};
 
 use crate::token;
-use crate::token::Token;
-use crate::environment::Environment;
+use crate::token::{Span, Token};
+use crate::environment::{Environment, Scope, Slot, resolve_local};
 use crate::callable;
-use crate::util::UnwindType;
+use crate::util;
+use crate::util::{IntegerOperator, UnwindType};
 use crate::lox_class::LoxInstance;
-use crate::error_reporter::ERROR_REPORTER;
+use crate::statement;
+use crate::statement::{FunctionType, Statement};
+use crate::lox_list;
+use crate::lox_map;
+use crate::lox_string;
+use crate::lox_map::{LoxMap, MapKey};
+use crate::gc;
+use crate::gc::{Trace, Tracer};
+use crate::diagnostic::{Diagnostic, RuntimeError};
 
 #[derive(Clone)]
 pub enum Value {
@@ -17,17 +26,21 @@ pub enum Value {
     Nil,
     Callable(Rc<dyn callable::Callable>),
     Instance(Rc<RefCell<LoxInstance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<LoxMap>>),
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (&Value::String(ref l), &Value::String(ref r)) => l == r,
-            (&Value::Number(l), &Value::Number(r)) => l == r,
-            (&Value::True, &Value::True) => true,
-            (&Value::False, &Value::False) => true,
-            (&Value::Nil, &Value::Nil) => true,
-            (&Value::Callable(ref l), &Value::Callable(ref r)) => Rc::ptr_eq(&l, &r),
+            (Value::String(l), Value::String(r)) => l == r,
+            (Value::Number(l), Value::Number(r)) => l == r,
+            (Value::True, Value::True) => true,
+            (Value::False, Value::False) => true,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Callable(l), Value::Callable(r)) => Rc::ptr_eq(l, r),
+            (Value::List(l), Value::List(r)) => Rc::ptr_eq(l, r),
+            (Value::Map(l), Value::Map(r)) => Rc::ptr_eq(l, r),
             _ => false,
         }
     }
@@ -41,13 +54,121 @@ impl fmt::Display for Value {
             Value::False => write!(f, "false"),
             Value::True => write!(f, "true"),
             Value::Nil => write!(f, "nil"),
-            Value::Callable(func) => write!(f, "callable {:?}({} arguments)", Rc::as_ptr(func), func.arity()),
+            Value::Callable(func) => match func.kind() {
+                callable::Kind::Function if func.name().is_empty() => write!(f, "<anonymous fn>"),
+                callable::Kind::Function => write!(f, "<fn {}>", func.name()),
+                callable::Kind::Native => write!(f, "<native fn {}>", func.name()),
+                callable::Kind::Class => write!(f, "{}", func.name()),
+            },
             Value::Instance(obj) => write!(f, "{}", obj.borrow()),
+            Value::List(list) => lox_list::fmt_list(list, f),
+            Value::Map(map) => lox_map::fmt_map(map, f),
+        }
+    }
+}
+
+// Hosts embedding the interpreter want to be able to unwrap and assert on values.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::Callable(callable) => tracer.pointer(callable),
+            Value::Instance(instance) => tracer.pointer(instance),
+            Value::List(list) => tracer.pointer(list),
+            Value::Map(map) => tracer.pointer(map),
+            _ => {},
         }
     }
 }
 
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::from_bool(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
 impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Number(_) => "number",
+            Value::True | Value::False => "boolean",
+            Value::Nil => "nil",
+            Value::Callable(_) => "function",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    // These check the type of an argument passed to a native function.
+
+    pub fn expect_number(&self) -> Result<f64, RuntimeError> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            _ => Err(self.type_error("number")),
+        }
+    }
+
+    pub fn expect_string(&self) -> Result<&str, RuntimeError> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(self.type_error("string")),
+        }
+    }
+
+    pub fn expect_bool(&self) -> Result<bool, RuntimeError> {
+        match self {
+            Value::True => Ok(true),
+            Value::False => Ok(false),
+            _ => Err(self.type_error("boolean")),
+        }
+    }
+
+    pub fn expect_list(&self) -> Result<&Rc<RefCell<Vec<Value>>>, RuntimeError> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(self.type_error("list")),
+        }
+    }
+
+    pub fn expect_map(&self) -> Result<&Rc<RefCell<LoxMap>>, RuntimeError> {
+        match self {
+            Value::Map(map) => Ok(map),
+            _ => Err(self.type_error("map")),
+        }
+    }
+
+    fn type_error(&self, expected: &str) -> RuntimeError {
+        RuntimeError::from(format!("Expected a {} but got {}.", expected, self.type_name()))
+    }
+
     pub fn from_bool(value: bool) -> Self {
         match value {
             true => Value::True,
@@ -56,11 +177,7 @@ impl Value {
     }
 
     pub fn is_truthy(&self) -> bool {
-        match self {
-            Value::Nil => false,
-            Value::False => false,
-            _ => true,
-        }
+        !matches!(self, Value::Nil | Value::False)
     }
 
     pub fn not(&self) -> Value {
@@ -70,15 +187,62 @@ impl Value {
         }
     }
 
-    pub fn as_number(&self, operator: Option<&Token>) -> Result<f64, UnwindType> {
+    pub fn as_number(&self, operator: &Token) -> Result<f64, UnwindType> {
         match self {
             Value::Number(n) => Ok(*n),
-            _ => {
-                if let Some(token) = operator {
-                    ERROR_REPORTER.lock().unwrap().runtime_error_on_token(token, "Operand must be a number.");
-                }
-                Err(UnwindType::Error)
+            _ => Err(UnwindType::error(operator, "Operand must be a number.")),
+        }
+    }
+
+    pub fn get_property(&self, name: &Token) -> Result<Value, String> {
+        match self {
+            Value::Instance(inst) => inst.borrow().get(name, inst.clone()),
+            Value::List(list) => lox_list::get_method(list, name),
+            Value::Map(map) => lox_map::get_method(map, name),
+            Value::String(string) => lox_string::get_method(string, name),
+            _ => Err("Only instances have properties.".to_string()),
+        }
+    }
+
+    pub fn set_property(&self, name: &Token, value: Value) -> Result<(), String> {
+        match self {
+            Value::Instance(inst) => {
+                inst.borrow_mut().set(name, value);
+                Ok(())
+            },
+            _ => Err("Only instances have fields.".to_string()),
+        }
+    }
+
+    pub fn get_index(&self, index: Value) -> Result<Value, String> {
+        match self {
+            Value::List(list) => {
+                let list = list.borrow();
+                let i = lox_list::index(&index, list.len(), false)?;
+                Ok(list[i].clone())
+            },
+            Value::Map(map) => {
+                let key = MapKey::new(index)?;
+                let value = map.borrow().get(&key).cloned();
+                value.ok_or_else(|| format!("Key '{}' not found in map.", key))
             },
+            _ => Err("Only lists and maps can be indexed.".to_string()),
+        }
+    }
+
+    pub fn set_index(&self, index: Value, value: Value) -> Result<(), String> {
+        match self {
+            Value::List(list) => {
+                let mut list = list.borrow_mut();
+                let i = lox_list::index(&index, list.len(), false)?;
+                list[i] = value;
+                Ok(())
+            },
+            Value::Map(map) => {
+                map.borrow_mut().insert(MapKey::new(index)?, value);
+                Ok(())
+            },
+            _ => Err("Only lists and maps can be indexed.".to_string()),
         }
     }
 }
@@ -87,6 +251,7 @@ impl Value {
 pub enum ClassType {
     None,
     Class,
+    Subclass,
 }
 
 #[derive(Clone)]
@@ -102,9 +267,11 @@ pub enum Expression {
         arguments: Vec<Expression>,
     },
     Grouping {
+        paren: Token,
         expression: Box<Expression>,
     },
     Literal {
+        token: Token,
         value: Value,
     },
     Unary {
@@ -113,12 +280,12 @@ pub enum Expression {
     },
     Variable {
         name: Token,
-        depth: Option<usize>,
+        slot: Option<Slot>,
     },
     Assignment {
         name: Token,
         value: Box<Expression>,
-        depth: Option<usize>,
+        slot: Option<Slot>,
     },
     Logical {
         left: Box<Expression>,
@@ -136,7 +303,44 @@ pub enum Expression {
     },
     This {
         keyword: Token,
-        depth: Option<usize>,
+        slot: Option<Slot>,
+    },
+    Super {
+        keyword: Token,
+        method: Token,
+        slot: Option<Slot>,
+    },
+    List {
+        bracket: Token,
+        elements: Vec<Expression>,
+    },
+    Map {
+        brace: Token,
+        entries: Vec<(Expression, Expression)>,
+    },
+    GetIndex {
+        object: Box<Expression>,
+        bracket: Token,
+        index: Box<Expression>,
+    },
+    SetIndex {
+        object: Box<Expression>,
+        bracket: Token,
+        index: Box<Expression>,
+        value: Box<Expression>,
+    },
+    // A string with expressions interpolated into it, made of those expressions and literals for
+    // the text around them.
+    Template {
+        quote: Token,
+        parts: Vec<Expression>,
+    },
+    // An anonymous function, either `fun (a) { ... }` or `(a) => ...`. The keyword is 'fun' or the
+    // arrow.
+    Lambda {
+        keyword: Token,
+        params: Vec<Token>,
+        body: Vec<Statement>,
     },
 }
 
@@ -145,19 +349,35 @@ impl fmt::Display for Expression {
         match self {
             Expression::Binary{left, operator, right}  => write!(f, "({} {} {})", operator.lexeme(), left, right),
             Expression::Call{callee, paren: _, arguments} => write!(f, "(call {} {:?})", callee, arguments),
-            Expression::Grouping{expression}  => write!(f, "(group {})", expression),
-            Expression::Literal{value} => write!(f, "{}", value),
+            Expression::Grouping{paren: _, expression}  => write!(f, "(group {})", expression),
+            Expression::Literal{token: _, value} => write!(f, "{}", value),
             Expression::Unary{operator, right} => write!(f, "({} {})", operator.lexeme(), right),
-            Expression::Variable{name, depth: _} => write!(f, "(variable {})", name.lexeme()),
-            Expression::Assignment{name, value, depth: _} => write!(f, "(assign {} {})", name.lexeme(), value),
+            Expression::Variable{name, slot} => write!(f, "(variable {}{})", name.lexeme(), fmt_slot(slot)),
+            Expression::Assignment{name, value, slot} => write!(f, "(assign {}{} {})", name.lexeme(), fmt_slot(slot), value),
             Expression::Logical{left, operator, right}  => write!(f, "({} {} {})", operator.lexeme(), left, right),
-            Expression::Get{object, name} => write!(f, "(property {} {})", object, name),
-            Expression::Set{object, name, value} => write!(f, "(property set {} {} {})", object, name, value),
-            Expression::This{keyword, depth: _} => write!(f, "{}", keyword.lexeme()),
+            Expression::Get{object, name} => write!(f, "(property {} {})", object, name.lexeme()),
+            Expression::Set{object, name, value} => write!(f, "(property set {} {} {})", object, name.lexeme(), value),
+            Expression::This{keyword, slot} => write!(f, "{}{}", keyword.lexeme(), fmt_slot(slot)),
+            Expression::Super{keyword: _, method, slot} => write!(f, "(super{} {})", fmt_slot(slot), method.lexeme()),
+            Expression::List{bracket: _, elements} => write!(f, "(list {:?})", elements),
+            Expression::Map{brace: _, entries} => write!(f, "(map {:?})", entries),
+            Expression::GetIndex{object, bracket: _, index} => write!(f, "(index {} {})", object, index),
+            Expression::SetIndex{object, bracket: _, index, value} => write!(f, "(index set {} {} {})", object, index, value),
+            Expression::Template{quote: _, parts} => write!(f, "(template {:?})", parts),
+            Expression::Lambda{keyword: _, params, body} => write!(f, "(lambda {})", statement::fmt_function(params, body)),
         }
     }
 }
 
+// Variables that have been resolved to locals are shown with where they were found. Globals, and
+// anything not resolved yet, have no slot.
+fn fmt_slot(slot: &Option<Slot>) -> String {
+    match slot {
+        Some(slot) => format!(" {}", slot),
+        None => String::new(),
+    }
+}
+
 impl fmt::Debug for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self)
@@ -165,49 +385,57 @@ impl fmt::Debug for Expression {
 }
 
 impl Expression {
+    // The source this expression was parsed from. Closing delimiters aren't kept, so the span of a
+    // call stops at its closing paren, but a grouping or list stops at its last inner expression.
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::Binary{left, operator: _, right} | Expression::Logical{left, operator: _, right} => left.span().to(right.span()),
+            Expression::Call{callee, paren, arguments: _} => callee.span().to(paren.span()),
+            Expression::Grouping{paren, expression} => paren.span().to(expression.span()),
+            Expression::Literal{token, value: _} => token.span(),
+            Expression::Unary{operator, right} => operator.span().to(right.span()),
+            Expression::Variable{name, slot: _} => name.span(),
+            Expression::Assignment{name, value, slot: _} => name.span().to(value.span()),
+            Expression::Get{object, name} => object.span().to(name.span()),
+            Expression::Set{object, name: _, value} => object.span().to(value.span()),
+            Expression::This{keyword, slot: _} => keyword.span(),
+            Expression::Super{keyword, method, slot: _} => keyword.span().to(method.span()),
+            Expression::List{bracket, elements} => match elements.last() {
+                Some(last) => bracket.span().to(last.span()),
+                None => bracket.span(),
+            },
+            Expression::Map{brace, entries} => match entries.last() {
+                Some((_, last)) => brace.span().to(last.span()),
+                None => brace.span(),
+            },
+            Expression::GetIndex{object, bracket, index: _} => object.span().to(bracket.span()),
+            Expression::SetIndex{object, bracket: _, index: _, value} => object.span().to(value.span()),
+            Expression::Template{quote, parts} => match parts.last() {
+                Some(last) => quote.span().to(last.span()),
+                None => quote.span(),
+            },
+            Expression::Lambda{keyword, params: _, body: _} => keyword.span(),
+        }
+    }
+
     pub fn interpret(&self, environment: Rc<RefCell<Environment>>) -> Result<Value, UnwindType> {
         match self {
-            Expression::Literal{value} => Ok(value.clone()),
-            Expression::Grouping{expression} => expression.interpret(environment),
+            Expression::Literal{token: _, value} => Ok(value.clone()),
+            Expression::Grouping{paren: _, expression} => expression.interpret(environment),
             Expression::Unary{operator, right} => {
                 let right = right.interpret(environment)?;
                 match operator.token_type() {
-                    token::Type::Minus => Ok(Value::Number(right.as_number(Some(operator))? * -1.0)),
+                    token::Type::Minus => Ok(Value::Number(-right.as_number(operator)?)),
                     token::Type::Bang => Ok(right.not()),
+                    token::Type::Tilde => util::bitwise_not(right.as_number(operator)?).map(Value::Number).map_err(|message| UnwindType::error(operator, message)),
                     _ => panic!("An invalid unary operator snuck in!")
                 }
             },
-            Expression::Binary{left, operator, right} => {
-                let left = left.interpret(environment.clone())?;
-                let right = right.interpret(environment.clone())?;
-
-                match operator.token_type() {
-                    token::Type::Greater => Ok(Value::from_bool(left.as_number(Some(operator))? > right.as_number(Some(operator))?)),
-                    token::Type::GreaterEqual => Ok(Value::from_bool(left.as_number(Some(operator))? >= right.as_number(Some(operator))?)),
-                    token::Type::Less => Ok(Value::from_bool(left.as_number(Some(operator))? < right.as_number(Some(operator))?)),
-                    token::Type::LessEqual => Ok(Value::from_bool(left.as_number(Some(operator))? <= right.as_number(Some(operator))?)),
-                    token::Type::BangEqual => Ok(Value::from_bool(left != right)),
-                    token::Type::EqualEqual => Ok(Value::from_bool(left == right)),
-                    token::Type::Minus => Ok(Value::Number(left.as_number(Some(operator))? - right.as_number(Some(operator))?)),
-                    token::Type::Slash => Ok(Value::Number(left.as_number(Some(operator))? / right.as_number(Some(operator))?)),
-                    token::Type::Star => Ok(Value::Number(left.as_number(Some(operator))? * right.as_number(Some(operator))?)),
-                    token::Type::Plus => {
-                        match (left, right) {
-                            (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
-                            (Value::String(l), Value::String(r)) => Ok(Value::String(l + &r)),
-                            _ => {
-                                ERROR_REPORTER.lock().unwrap().runtime_error_on_token(operator, "Operands must be either two numbers or two strings.");
-                                Err(UnwindType::Error)
-                            },
-                        }
-                    },
-                    _ => panic!("An invalid binary operator snuck in!")
-                }
-            },
-            Expression::Variable{name, depth} => environment.borrow().get_at(*depth, name).ok_or(UnwindType::Error),
-            Expression::Assignment{name, value, depth} => {
+            Expression::Binary{left, operator, right} => interpret_binary(left, operator, right, environment),
+            Expression::Variable{name, slot} => environment.borrow().get_at(*slot, name).map_err(UnwindType::Error),
+            Expression::Assignment{name, value, slot} => {
                 let value = value.interpret(environment.clone())?;
-                environment.borrow_mut().assign_at(*depth, name.clone(), value.clone()).ok_or(UnwindType::Error)?;
+                environment.borrow_mut().assign_at(*slot, name, value.clone()).map_err(UnwindType::Error)?;
                 Ok(value)
             },
             Expression::Logical{left, operator, right} => {
@@ -218,118 +446,247 @@ impl Expression {
                     _ => panic!("An invalid logical operator snuck in!"),
                 }
             },
-            Expression::Call{callee, paren, arguments} => {
-                let callee = callee.interpret(environment.clone())?;
-                let mut args = Vec::new();
-                for argument in arguments {
-                    args.push(argument.interpret(environment.clone())?);
-                }
-                match callee {
-                    Value::Callable(func) => {
-                        if args.len() != func.arity() {
-                            ERROR_REPORTER.lock().unwrap().runtime_error_on_token(paren, &format!("Expected {} arguments but got {}.", func.arity(), args.len()));
-                            Err(UnwindType::Error)
-                        } else {
-                            func.call(environment.clone(), args).ok_or(UnwindType::Error)
-                        }
-                    }
-                    _ => {
-                        ERROR_REPORTER.lock().unwrap().runtime_error_on_token(paren, "Can only call functions and classes.");
-                        Err(UnwindType::Error)
-                    }
-                }
-            },
+            Expression::Call{callee, paren, arguments} => interpret_call(callee, paren, arguments, environment),
             Expression::Get{object, name} => {
+                let object = object.interpret(environment)?;
+                object.get_property(name).map_err(|message| {
+                    UnwindType::error(name, &message)
+                })
+            },
+            Expression::Set{object, name, value} => {
                 let object = object.interpret(environment.clone())?;
-                match object {
-                    Value::Instance(inst) => {
-                        let inst_ref = inst.clone();
-                        inst.borrow().get(name, inst_ref).ok_or(UnwindType::Error)
-                    },
-                    _ => {
-                        ERROR_REPORTER.lock().unwrap().error_on_token(name, "Only instances have properties.");
-                        Err(UnwindType::Error)
+                let value = value.interpret(environment)?;
+                match object.set_property(name, value.clone()) {
+                    Ok(()) => Ok(value),
+                    Err(message) => {
+                        Err(UnwindType::error(name, &message))
                     },
                 }
             },
-            Expression::Set{object, name, value} => {
+            Expression::This{keyword, slot} => environment.borrow().get_at(*slot, keyword).map_err(UnwindType::Error),
+            Expression::Super{keyword, method, slot} => interpret_super(keyword, method, slot, environment),
+            Expression::List{bracket: _, elements} => {
+                let mut values = Vec::new();
+                for element in elements {
+                    values.push(element.interpret(environment.clone())?);
+                }
+                Ok(Value::List(gc::new_list(values)))
+            },
+            Expression::Template{quote: _, parts} => {
+                let mut string = String::new();
+                for part in parts {
+                    string += &part.interpret(environment.clone())?.to_string();
+                }
+                Ok(Value::String(string))
+            },
+            Expression::Map{brace, entries} => interpret_map(brace, entries, environment),
+            Expression::GetIndex{object, bracket, index} => {
                 let object = object.interpret(environment.clone())?;
-                match object {
-                    Value::Instance(inst) => {
-                        let value = value.interpret(environment)?;
-                        inst.borrow_mut().set(name, value.clone());
-                        Ok(value)
-                    },
-                    _ => {
-                        ERROR_REPORTER.lock().unwrap().error_on_token(name, "Only instances have properties.");
-                        Err(UnwindType::Error)
+                let index = index.interpret(environment)?;
+                object.get_index(index).map_err(|message| {
+                    UnwindType::error(bracket, &message)
+                })
+            },
+            Expression::SetIndex{object, bracket, index, value} => {
+                let object = object.interpret(environment.clone())?;
+                let index = index.interpret(environment.clone())?;
+                let value = value.interpret(environment)?;
+                match object.set_index(index, value.clone()) {
+                    Ok(()) => Ok(value),
+                    Err(message) => {
+                        Err(UnwindType::error(bracket, &message))
                     },
                 }
             },
-            Expression::This{keyword, depth} => environment.borrow().get_at(*depth, keyword).ok_or(UnwindType::Error),
+            Expression::Lambda{keyword, params, body} => {
+                // Anonymous functions are the ones with an empty name.
+                let name = Token::with_span(token::Type::Fun, String::new(), keyword.span());
+                Ok(Value::Callable(gc::new_callable(callable::LoxCallable::new(name, params.clone(), body.clone(), environment, false))))
+            },
         }
     }
 
-    pub fn resolve(&mut self, scopes: &mut Vec<HashMap<String, bool>>, class_type: &ClassType) {
+    pub fn resolve(&mut self, scopes: &mut Vec<Scope>, class_type: &ClassType, diagnostics: &mut Vec<Diagnostic>) {
         match self {
-            Expression::Variable{name, depth} => {
+            Expression::Variable{name, slot} => {
                 if let Some(last) = scopes.last() {
-                    if let Some(is_defined) = last.get(name.lexeme()) {
-                        if !is_defined {
-                            ERROR_REPORTER.lock().unwrap().error_on_token(name, "Can't read local variable in it's own initializer.");
-                        }
-                    }
-                }
-                for i in (0..scopes.len()).rev() {
-                    if scopes.get(i).unwrap().contains_key(name.lexeme()) {
-                        *depth = Some(scopes.len() - 1 - i);
-                        break;
+                    if last.is_declared_but_undefined(name.lexeme()) {
+                        diagnostics.push(Diagnostic::on_token(name, "Can't read local variable in it's own initializer."));
                     }
                 }
+                *slot = resolve_local(scopes, name.lexeme());
             },
-            Expression::Assignment{name, value, depth} => {
-                value.resolve(scopes, class_type);
-                for i in (0..scopes.len()).rev() {
-                    if scopes.get(i).unwrap().contains_key(name.lexeme()) {
-                        *depth = Some(scopes.len() - 1 - i);
-                        break;
-                    }
-                }
+            Expression::Assignment{name, value, slot} => {
+                value.resolve(scopes, class_type, diagnostics);
+                *slot = resolve_local(scopes, name.lexeme());
             },
             Expression::Binary{left, operator: _, right} => {
-                left.resolve(scopes, class_type);
-                right.resolve(scopes, class_type);
+                left.resolve(scopes, class_type, diagnostics);
+                right.resolve(scopes, class_type, diagnostics);
             },
             Expression::Call{callee, paren: _, arguments} => {
-                callee.resolve(scopes, class_type);
+                callee.resolve(scopes, class_type, diagnostics);
                 for argument in arguments {
-                    argument.resolve(scopes, class_type);
+                    argument.resolve(scopes, class_type, diagnostics);
                 }
             },
-            Expression::Grouping{expression} => expression.resolve(scopes, class_type),
-            Expression::Literal{value: _} => {},
+            Expression::Grouping{paren: _, expression} => expression.resolve(scopes, class_type, diagnostics),
+            Expression::Literal{..} => {},
             Expression::Logical{left, operator: _, right} => {
-                left.resolve(scopes, class_type);
-                right.resolve(scopes, class_type);
+                left.resolve(scopes, class_type, diagnostics);
+                right.resolve(scopes, class_type, diagnostics);
             },
-            Expression::Unary{operator: _, right} => right.resolve(scopes, class_type),
-            Expression::Get{object, name: _} => object.resolve(scopes, class_type),
+            Expression::Unary{operator: _, right} => right.resolve(scopes, class_type, diagnostics),
+            Expression::Get{object, name: _} => object.resolve(scopes, class_type, diagnostics),
             Expression::Set{object, name: _, value} => {
-                value.resolve(scopes, class_type);
-                object.resolve(scopes, class_type);
+                value.resolve(scopes, class_type, diagnostics);
+                object.resolve(scopes, class_type, diagnostics);
             },
-            Expression::This{keyword, depth} => {
+            Expression::This{keyword, slot} => {
                 if *class_type == ClassType::None {
-                    ERROR_REPORTER.lock().unwrap().error_on_token(keyword, "Can't use 'this' outside of a class.")
+                    diagnostics.push(Diagnostic::on_token(keyword, "Can't use 'this' outside of a class."))
                 } else {
-                    for i in (0..scopes.len()).rev() {
-                        if scopes.get(i).unwrap().contains_key(keyword.lexeme()) {
-                            *depth = Some(scopes.len() - 1 - i);
-                            break;
-                        }
-                    }
+                    *slot = resolve_local(scopes, keyword.lexeme());
                 }
             },
+            Expression::Super{keyword, method: _, slot} => {
+                match class_type {
+                    ClassType::None => diagnostics.push(Diagnostic::on_token(keyword, "Can't use 'super' outside of a class.")),
+                    ClassType::Class => diagnostics.push(Diagnostic::on_token(keyword, "Can't use 'super' in a class with no superclass.")),
+                    ClassType::Subclass => *slot = resolve_local(scopes, keyword.lexeme()),
+                }
+            },
+            Expression::List{bracket: _, elements} => {
+                for element in elements {
+                    element.resolve(scopes, class_type, diagnostics);
+                }
+            },
+            Expression::Template{quote: _, parts} => {
+                for part in parts {
+                    part.resolve(scopes, class_type, diagnostics);
+                }
+            },
+            Expression::Map{brace: _, entries} => {
+                for (key, value) in entries {
+                    key.resolve(scopes, class_type, diagnostics);
+                    value.resolve(scopes, class_type, diagnostics);
+                }
+            },
+            Expression::GetIndex{object, bracket: _, index} => {
+                object.resolve(scopes, class_type, diagnostics);
+                index.resolve(scopes, class_type, diagnostics);
+            },
+            Expression::SetIndex{object, bracket: _, index, value} => {
+                object.resolve(scopes, class_type, diagnostics);
+                index.resolve(scopes, class_type, diagnostics);
+                value.resolve(scopes, class_type, diagnostics);
+            },
+            Expression::Lambda{keyword: _, params, body} => statement::resolve_function(params, body, &FunctionType::Function, scopes, class_type, diagnostics),
+        }
+    }
+}
+
+// The larger cases of Expression::interpret, kept out of it so that the stack frame it needs for
+// each level of a Lox expression stays small.
+fn interpret_binary(left: &Expression, operator: &Token, right: &Expression, environment: Rc<RefCell<Environment>>) -> Result<Value, UnwindType> {
+    let left = left.interpret(environment.clone())?;
+    let right = right.interpret(environment.clone())?;
+
+    match operator.token_type() {
+        token::Type::Greater => Ok(Value::from_bool(left.as_number(operator)? > right.as_number(operator)?)),
+        token::Type::GreaterEqual => Ok(Value::from_bool(left.as_number(operator)? >= right.as_number(operator)?)),
+        token::Type::Less => Ok(Value::from_bool(left.as_number(operator)? < right.as_number(operator)?)),
+        token::Type::LessEqual => Ok(Value::from_bool(left.as_number(operator)? <= right.as_number(operator)?)),
+        token::Type::BangEqual => Ok(Value::from_bool(left != right)),
+        token::Type::EqualEqual => Ok(Value::from_bool(left == right)),
+        token::Type::Minus => Ok(Value::Number(left.as_number(operator)? - right.as_number(operator)?)),
+        token::Type::Slash => Ok(Value::Number(left.as_number(operator)? / right.as_number(operator)?)),
+        token::Type::Star => Ok(Value::Number(left.as_number(operator)? * right.as_number(operator)?)),
+        token::Type::Percent => Ok(Value::Number(left.as_number(operator)? % right.as_number(operator)?)),
+        token::Type::StarStar => Ok(Value::Number(left.as_number(operator)?.powf(right.as_number(operator)?))),
+        token::Type::TildeSlash | token::Type::Ampersand | token::Type::Pipe | token::Type::Caret | token::Type::LessLess | token::Type::GreaterGreater => {
+            let (left, right) = (left.as_number(operator)?, right.as_number(operator)?);
+            let result = match operator.token_type() {
+                token::Type::TildeSlash => util::integer_divide(left, right),
+                token::Type::Ampersand => util::integer_operation(IntegerOperator::And, left, right),
+                token::Type::Pipe => util::integer_operation(IntegerOperator::Or, left, right),
+                token::Type::Caret => util::integer_operation(IntegerOperator::Xor, left, right),
+                token::Type::LessLess => util::integer_operation(IntegerOperator::ShiftLeft, left, right),
+                _ => util::integer_operation(IntegerOperator::ShiftRight, left, right),
+            };
+            result.map(Value::Number).map_err(|message| UnwindType::error(operator, message))
+        },
+        token::Type::Plus => {
+            match (left, right) {
+                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
+                (Value::String(l), Value::String(r)) => Ok(Value::String(l + &r)),
+                _ => {
+                    Err(UnwindType::error(operator, "Operands must be either two numbers or two strings."))
+                },
+            }
+        },
+        _ => panic!("An invalid binary operator snuck in!")
+    }
+}
+
+fn interpret_call(callee: &Expression, paren: &Token, arguments: &[Expression], environment: Rc<RefCell<Environment>>) -> Result<Value, UnwindType> {
+    let callee = callee.interpret(environment.clone())?;
+    let mut args = Vec::new();
+    for argument in arguments {
+        args.push(argument.interpret(environment.clone())?);
+    }
+    match callee {
+        Value::Callable(func) => {
+            if !func.arity().accepts(args.len()) {
+                Err(UnwindType::error(paren, &format!("Expected {} arguments but got {}.", func.arity(), args.len())))
+            } else {
+                let name = func.frame_name().map(str::to_string);
+                func.call(args).map_err(|diagnostic| match name {
+                    // Errors without a span come from making the call, like a stack overflow, rather
+                    // than from inside the function, so it doesn't get a frame of its own.
+                    Some(name) if diagnostic.span.is_some() => {
+                        UnwindType::Error(diagnostic.unwound_from(&name, paren.span()))
+                    }
+                    _ => UnwindType::Error(diagnostic.or_at(paren.span())),
+                })
+            }
+        }
+        _ => {
+            Err(UnwindType::error(paren, "Can only call functions and classes."))
+        }
+    }
+}
+
+fn interpret_super(keyword: &Token, method: &Token, slot: &Option<Slot>, environment: Rc<RefCell<Environment>>) -> Result<Value, UnwindType> {
+    let superclass = environment.borrow().get_at(*slot, keyword).map_err(UnwindType::Error)?;
+    // The environment binding "this" is always just inside the one binding "super".
+    let this_token = Token::with_span(token::Type::This, "this".to_string(), keyword.span());
+    let this_slot = slot.map(|slot| Slot{depth: slot.depth - 1, index: 0});
+    let instance = environment.borrow().get_at(this_slot, &this_token).map_err(UnwindType::Error)?;
+    match (superclass, instance) {
+        (Value::Callable(superclass), Value::Instance(inst)) => {
+            let superclass = superclass.as_class().expect("'super' resolved to something that isn't a class!");
+            if let Some(superclass_method) = superclass.find_method(method.lexeme()) {
+                Ok(Value::Callable(superclass_method.bind(inst)))
+            } else {
+                Err(UnwindType::error(method, &format!("Undefined property '{}'.", method.lexeme())))
+            }
+        },
+        _ => panic!("'super' or 'this' resolved to an unexpected value!"),
+    }
+}
+
+fn interpret_map(brace: &Token, entries: &[(Expression, Expression)], environment: Rc<RefCell<Environment>>) -> Result<Value, UnwindType> {
+    let mut map = LoxMap::new();
+    for (key, value) in entries {
+        let key = key.interpret(environment.clone())?;
+        let value = value.interpret(environment.clone())?;
+        match MapKey::new(key) {
+            Ok(key) => map.insert(key, value),
+            Err(message) => {
+                return Err(UnwindType::error(brace, &message));
+            },
         }
     }
+    Ok(Value::Map(gc::new_map(map)))
 }
@@ -0,0 +1,350 @@
+use std::{cell::RefCell, collections::HashMap, rc::{Rc, Weak}};
+
+use crate::callable::Callable;
+use crate::environment::Environment;
+use crate::expression::Value;
+use crate::lox_class::LoxInstance;
+use crate::lox_map::LoxMap;
+use crate::vm::{Globals, Upvalue};
+
+/*
+* NOTE: Values are reference counted, which frees everything except cycles (an instance holding a
+* method bound to itself, a closure stored in the environment it closes over, ...). To find those,
+* every heap object that can take part in a cycle is allocated through this module, which keeps a
+* weak reference to it.
+*
+* A collection works out which objects are referenced from outside the heap by subtracting the
+* references objects hold to each other from their strong counts. Anything with references left
+* over is held by the interpreter itself (an environment in use, a value on the VM stack, a
+* temporary in the middle of evaluating an expression), so it's a root. Everything reachable from
+* the roots is marked, and everything else is garbage kept alive only by cycles. Sweeping clears
+* the contents of the garbage, which breaks the cycles and lets the reference counts free it.
+*
+* This means there is no need to find the roots by hand, and a collection can safely happen at
+* any allocation. Objects that are mutably borrowed at that moment can't be looked inside, so
+* they (and everything they refer to) are conservatively kept.
+*/
+
+const INITIAL_THRESHOLD: usize = 1024;
+const THRESHOLD_GROWTH_FACTOR: usize = 2;
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::new());
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcStats {
+    // Objects allocated through the heap since the thread started.
+    pub allocations: usize,
+    pub collections: usize,
+    // Objects freed by collections, i.e. objects that would have leaked through cycles.
+    pub reclaimed: usize,
+    // Objects that are still alive.
+    pub live: usize,
+}
+
+// Anything that holds references to heap objects.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+// Records which heap objects something refers to, by address.
+pub struct Tracer {
+    children: Vec<usize>,
+}
+
+impl Tracer {
+    pub fn pointer<T: ?Sized>(&mut self, object: &Rc<T>) {
+        self.children.push(Rc::as_ptr(object) as *const () as usize);
+    }
+}
+
+impl<T: ?Sized> Trace for Rc<RefCell<T>> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.pointer(self);
+    }
+}
+
+impl Trace for Vec<Value> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self {
+            value.trace(tracer);
+        }
+    }
+}
+
+// Strings don't refer to anything, but are the receivers of string methods.
+impl Trace for Rc<str> {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+enum WeakObject {
+    Environment(Weak<RefCell<Environment>>),
+    Globals(Weak<RefCell<HashMap<String, Value>>>),
+    Instance(Weak<RefCell<LoxInstance>>),
+    List(Weak<RefCell<Vec<Value>>>),
+    Map(Weak<RefCell<LoxMap>>),
+    Callable(Weak<dyn Callable>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+}
+
+impl WeakObject {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            WeakObject::Environment(weak) => weak.upgrade().map(Object::Environment),
+            WeakObject::Globals(weak) => weak.upgrade().map(Object::Globals),
+            WeakObject::Instance(weak) => weak.upgrade().map(Object::Instance),
+            WeakObject::List(weak) => weak.upgrade().map(Object::List),
+            WeakObject::Map(weak) => weak.upgrade().map(Object::Map),
+            WeakObject::Callable(weak) => weak.upgrade().map(Object::Callable),
+            WeakObject::Upvalue(weak) => weak.upgrade().map(Object::Upvalue),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            WeakObject::Environment(weak) => weak.strong_count() > 0,
+            WeakObject::Globals(weak) => weak.strong_count() > 0,
+            WeakObject::Instance(weak) => weak.strong_count() > 0,
+            WeakObject::List(weak) => weak.strong_count() > 0,
+            WeakObject::Map(weak) => weak.strong_count() > 0,
+            WeakObject::Callable(weak) => weak.strong_count() > 0,
+            WeakObject::Upvalue(weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+enum Object {
+    Environment(Rc<RefCell<Environment>>),
+    Globals(Globals),
+    Instance(Rc<RefCell<LoxInstance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<LoxMap>>),
+    Callable(Rc<dyn Callable>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+}
+
+impl Object {
+    fn downgrade(&self) -> WeakObject {
+        match self {
+            Object::Environment(rc) => WeakObject::Environment(Rc::downgrade(rc)),
+            Object::Globals(rc) => WeakObject::Globals(Rc::downgrade(rc)),
+            Object::Instance(rc) => WeakObject::Instance(Rc::downgrade(rc)),
+            Object::List(rc) => WeakObject::List(Rc::downgrade(rc)),
+            Object::Map(rc) => WeakObject::Map(Rc::downgrade(rc)),
+            Object::Callable(rc) => WeakObject::Callable(Rc::downgrade(rc)),
+            Object::Upvalue(rc) => WeakObject::Upvalue(Rc::downgrade(rc)),
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Object::Environment(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Object::Globals(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Object::Instance(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Object::List(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Object::Map(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Object::Callable(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Object::Upvalue(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Environment(rc) => Rc::strong_count(rc),
+            Object::Globals(rc) => Rc::strong_count(rc),
+            Object::Instance(rc) => Rc::strong_count(rc),
+            Object::List(rc) => Rc::strong_count(rc),
+            Object::Map(rc) => Rc::strong_count(rc),
+            Object::Callable(rc) => Rc::strong_count(rc),
+            Object::Upvalue(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // Returns false if the object is borrowed, and so couldn't be looked inside.
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        match self {
+            Object::Environment(rc) => rc.try_borrow().map(|object| object.trace(tracer)).is_ok(),
+            Object::Globals(rc) => rc.try_borrow().map(|globals| {
+                for value in globals.values() {
+                    value.trace(tracer);
+                }
+            }).is_ok(),
+            Object::Instance(rc) => rc.try_borrow().map(|object| object.trace(tracer)).is_ok(),
+            Object::List(rc) => rc.try_borrow().map(|object| object.trace(tracer)).is_ok(),
+            Object::Map(rc) => rc.try_borrow().map(|object| object.trace(tracer)).is_ok(),
+            Object::Callable(rc) => {
+                rc.trace(tracer);
+                true
+            },
+            Object::Upvalue(rc) => rc.try_borrow().map(|object| object.trace(tracer)).is_ok(),
+        }
+    }
+
+    // Drops every reference this object holds. Callables are immutable, so a cycle can't be made
+    // of callables alone; clearing everything else is enough to break it.
+    fn clear(&self) {
+        match self {
+            Object::Environment(rc) => rc.borrow_mut().clear(),
+            Object::Globals(rc) => rc.borrow_mut().clear(),
+            Object::Instance(rc) => rc.borrow_mut().clear(),
+            Object::List(rc) => rc.borrow_mut().clear(),
+            Object::Map(rc) => rc.borrow_mut().clear(),
+            Object::Callable(_) => {},
+            Object::Upvalue(rc) => *rc.borrow_mut() = Upvalue::Closed(Value::Nil),
+        }
+    }
+}
+
+struct Heap {
+    objects: Vec<WeakObject>,
+    threshold: usize,
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Heap {
+    fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    fn collect(&mut self) -> usize {
+        let objects: Vec<Object> = self.objects.iter().filter_map(|object| object.upgrade()).collect();
+        let indices: HashMap<usize, usize> = objects.iter().enumerate().map(|(i, object)| (object.address(), i)).collect();
+
+        // Our own upgraded reference doesn't count.
+        let mut external_references: Vec<usize> = objects.iter().map(|object| object.strong_count() - 1).collect();
+        let mut children = Vec::with_capacity(objects.len());
+        for object in &objects {
+            let mut tracer = Tracer {
+                children: Vec::new(),
+            };
+            if object.trace(&mut tracer) {
+                let object_children: Vec<usize> = tracer.children.iter().filter_map(|address| indices.get(address).copied()).collect();
+                for &child in &object_children {
+                    external_references[child] -= 1;
+                }
+                children.push(Some(object_children));
+            } else {
+                children.push(None);
+            }
+        }
+
+        let mut marked = vec![false; objects.len()];
+        let mut gray: Vec<usize> = (0..objects.len()).filter(|&i| external_references[i] > 0 || children[i].is_none()).collect();
+        while let Some(i) = gray.pop() {
+            if marked[i] {
+                continue;
+            }
+            marked[i] = true;
+            if let Some(object_children) = &children[i] {
+                gray.extend(object_children.iter().filter(|&&child| !marked[child]));
+            }
+        }
+
+        let mut reclaimed = 0;
+        for (object, &is_marked) in objects.iter().zip(&marked) {
+            if !is_marked {
+                object.clear();
+                reclaimed += 1;
+            }
+        }
+        self.objects = objects.iter().zip(&marked).filter(|(_, &is_marked)| is_marked).map(|(object, _)| object.downgrade()).collect();
+        self.threshold = (self.objects.len() * THRESHOLD_GROWTH_FACTOR).max(INITIAL_THRESHOLD);
+        self.stats.collections += 1;
+        self.stats.reclaimed += reclaimed;
+        // Dropping `objects` here frees the garbage.
+        reclaimed
+    }
+}
+
+fn track(object: Object) {
+    let should_collect = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(object.downgrade());
+        heap.stats.allocations += 1;
+        heap.stress || heap.objects.len() >= heap.threshold
+    });
+    // The new object is still referenced from here, so it's safe from this collection.
+    drop(object);
+    if should_collect {
+        collect();
+    }
+}
+
+pub(crate) fn new_environment(environment: Environment) -> Rc<RefCell<Environment>> {
+    let environment = Rc::new(RefCell::new(environment));
+    track(Object::Environment(environment.clone()));
+    environment
+}
+
+pub(crate) fn new_globals() -> Globals {
+    let globals = Rc::new(RefCell::new(HashMap::new()));
+    track(Object::Globals(globals.clone()));
+    globals
+}
+
+pub(crate) fn new_instance(instance: LoxInstance) -> Rc<RefCell<LoxInstance>> {
+    let instance = Rc::new(RefCell::new(instance));
+    track(Object::Instance(instance.clone()));
+    instance
+}
+
+pub(crate) fn new_list(list: Vec<Value>) -> Rc<RefCell<Vec<Value>>> {
+    let list = Rc::new(RefCell::new(list));
+    track(Object::List(list.clone()));
+    list
+}
+
+pub(crate) fn new_map(map: LoxMap) -> Rc<RefCell<LoxMap>> {
+    let map = Rc::new(RefCell::new(map));
+    track(Object::Map(map.clone()));
+    map
+}
+
+pub(crate) fn new_callable<T: Callable + 'static>(callable: T) -> Rc<T> {
+    let callable = Rc::new(callable);
+    track(Object::Callable(callable.clone()));
+    callable
+}
+
+pub(crate) fn new_upvalue(upvalue: Upvalue) -> Rc<RefCell<Upvalue>> {
+    let upvalue = Rc::new(RefCell::new(upvalue));
+    track(Object::Upvalue(upvalue.clone()));
+    upvalue
+}
+
+// Runs a collection now, returning how many objects were reclaimed.
+pub fn collect() -> usize {
+    // Take the objects out of the heap while collecting, so that anything dropped during the
+    // sweep can't find the heap borrowed.
+    let mut heap = HEAP.with(|heap| heap.replace(Heap::new()));
+    let reclaimed = heap.collect();
+    HEAP.with(|current| {
+        let mut current = current.borrow_mut();
+        heap.stats.allocations += current.stats.allocations;
+        heap.objects.append(&mut current.objects);
+        *current = heap;
+    });
+    reclaimed
+}
+
+// In stress mode, every allocation triggers a full collection.
+pub fn set_stress_mode(stress: bool) {
+    HEAP.with(|heap| heap.borrow_mut().stress = stress);
+}
+
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        GcStats {
+            live: heap.objects.iter().filter(|object| object.is_alive()).count(),
+            ..heap.stats
+        }
+    })
+}
@@ -4,7 +4,10 @@ use std::cell::RefCell;
 use crate::expression::Value;
 use crate::statement::Statement;
 use crate::environment::Environment;
-use crate::callable::NativeClock;
+use crate::natives;
+use crate::gc;
+use crate::util::UnwindType;
+use crate::diagnostic::Diagnostic;
 
 pub struct Interpreter {
     environment: Rc<RefCell<Environment>>,
@@ -12,18 +15,47 @@ pub struct Interpreter {
 
 impl Interpreter {
     pub fn new() -> Self {
-        let environment = Rc::new(RefCell::new(Environment::new()));
-        environment.borrow_mut().define("clock".to_owned(), Value::Callable(Rc::new(NativeClock::new())));
+        let environment = gc::new_environment(Environment::new());
+        for (name, value) in natives::globals() {
+            environment.borrow_mut().define(name, value);
+        }
         Self {
             environment
         }
     }
 
-    pub fn interpret(&mut self, statements: Vec<Statement>) {
+    // Returns the value of the last statement if it's an expression, or nil otherwise.
+    pub(crate) fn interpret(&mut self, statements: Vec<Statement>) -> Result<Value, Diagnostic> {
+        let mut result = Value::Nil;
         for statement in statements {
-            if let Err(_) = statement.interpret(self.environment.clone()) {
-                break;
+            let unwind = match statement {
+                Statement::Expression{expression} => expression.interpret(self.environment.clone()).map(|value| result = value),
+                statement => statement.interpret(self.environment.clone()).map(|_| result = Value::Nil),
+            };
+            match unwind {
+                Ok(()) => {},
+                Err(UnwindType::Error(diagnostic)) => return Err(diagnostic),
+                Err(_) => panic!("A return or loop control statement escaped to the top level!"),
             }
         }
+        Ok(result)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.environment.borrow().global(name)
+    }
+
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.environment.borrow().globals()
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.environment.borrow_mut().define(name, value);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
     }
 }
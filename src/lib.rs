@@ -0,0 +1,32 @@
+#[macro_use]
+extern crate lazy_static;
+
+mod token;
+mod scanner;
+mod lox;
+mod util;
+mod diagnostic;
+mod expression;
+mod parser;
+mod interpreter;
+mod statement;
+mod environment;
+mod callable;
+mod lox_class;
+mod lox_list;
+mod lox_map;
+mod lox_string;
+mod chunk;
+mod compiler;
+mod vm;
+mod natives;
+pub mod gc;
+pub mod conformance;
+
+pub use lox::{Backend, Dump, Lox};
+pub use interpreter::Interpreter;
+pub use vm::Vm;
+pub use expression::Value;
+pub use diagnostic::{Details, Diagnostic, LoxError, RuntimeError, Severity, Source, StackFrame};
+pub use token::Span;
+pub use callable::Arity;
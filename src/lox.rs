@@ -1,80 +1,399 @@
-use std::process;
+use std::fs;
 use std::io;
+use std::rc::Rc;
 use std::io::Write;
-use std::fs;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use crate::scanner::Scanner;
 use crate::parser::Parser;
 use crate::interpreter::Interpreter;
-use crate::error_reporter::ERROR_REPORTER;
-use crate::statement::FunctionType;
+use crate::compiler::Compiler;
+use crate::vm::Vm;
+use crate::expression::Value;
+use crate::diagnostic::{Diagnostic, LoxError, RuntimeError, Source};
+use crate::callable::{Arity, NativeFunction};
+use crate::statement::{FunctionType, LoopType, Statement};
 use crate::expression::ClassType;
-use crate::util::{EX_DATAERR, EX_SOFTWARE};
+use crate::token::{self, Token};
 
-// TODO: This reeks of OOP.
-pub struct Lox {
-    interpreter: Interpreter,
+// Both backends share the scanner, parser and resolver; they only differ in how the resolved
+// statements are executed.
+pub enum Backend {
+    TreeWalker(Interpreter),
+    Vm(Vm),
 }
 
-impl Lox {
-    pub fn new() -> Self {
-        Self {
-            interpreter: Interpreter::new(),
+impl Backend {
+    // A backend of the same kind, with nothing but the natives defined. Modules are run in these.
+    fn fresh(&self) -> Self {
+        match self {
+            Backend::TreeWalker(_) => Backend::TreeWalker(Interpreter::new()),
+            Backend::Vm(_) => Backend::Vm(Vm::new()),
         }
     }
 
-    pub fn run_file(&mut self, path: &str) {
-        self.run(&fs::read_to_string(path).expect(&format!("Failed to open source file: {}", path)));
-        if ERROR_REPORTER.lock().unwrap().had_error {
-            process::exit(EX_DATAERR);
+    fn run(&mut self, statements: Vec<Statement>) -> Result<Value, LoxError> {
+        match self {
+            Backend::TreeWalker(interpreter) => interpreter.interpret(statements),
+            Backend::Vm(vm) => {
+                let function = Compiler::new().compile(&statements).map_err(LoxError::Compile)?;
+                vm.interpret(function)
+            },
+        }.map_err(LoxError::Runtime)
+    }
+
+    fn get_global(&self, name: &str) -> Option<Value> {
+        match self {
+            Backend::TreeWalker(interpreter) => interpreter.get_global(name),
+            Backend::Vm(vm) => vm.get_global(name),
         }
-        if ERROR_REPORTER.lock().unwrap().had_runtime_error {
-            process::exit(EX_SOFTWARE);
+    }
+
+    fn define_global(&mut self, name: &str, value: Value) {
+        match self {
+            Backend::TreeWalker(interpreter) => interpreter.define_global(name, value),
+            Backend::Vm(vm) => vm.define_global(name, value),
+        }
+    }
+
+    fn globals(&self) -> Vec<(String, Value)> {
+        match self {
+            Backend::TreeWalker(interpreter) => interpreter.globals(),
+            Backend::Vm(vm) => vm.globals(),
+        }
+    }
+}
+
+const REPL_HELP: &str = "\
+Type Lox to run it. The values of expressions are shown, and their semicolons can be left off.
+Commands:
+  :help           Show this message.
+  :env            List the globals and their values.
+  :load <file>    Run a file.
+  :reset          Forget everything defined so far.
+  :ast <expr>     Show how an expression is parsed.
+  :tokens <src>   Show the tokens some source is scanned into.
+  :time <expr>    Run an entry and show how long it took.";
+
+// Stages of running a script whose output can be shown instead, for debugging the interpreter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dump {
+    Tokens,
+    // The syntax tree, as parsed.
+    Ast,
+    // The syntax tree once resolved, with the slot each local variable was found in.
+    Resolved,
+}
+
+// TODO: This reeks of OOP.
+pub struct Lox {
+    backend: Backend,
+    // The names each module declared, by canonical path, so that each one is only run once.
+    modules: HashMap<PathBuf, Vec<(String, Value)>>,
+    // The files currently being loaded, outermost first, with the names they're shown under. Finding
+    // one of these again means there's a cycle.
+    loading: Vec<(PathBuf, String)>,
+    // Globals defined by the host, which modules get as well.
+    host_globals: HashMap<String, Value>,
+}
+
+impl Lox {
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            modules: HashMap::new(),
+            loading: Vec::new(),
+            host_globals: HashMap::new(),
         }
     }
 
+    // Reads and runs entries until the end of input. The values of expressions are echoed, and an
+    // entry can run over several lines as long as brackets are left open.
     pub fn run_prompt(&mut self) {
-        let mut input_buffer = String::new();
+        let mut entry = String::new();
         loop {
-            print!("> ");
+            print!("{}", if entry.is_empty() { "> " } else { "... " });
             io::stdout().flush().expect("Error flushing stdout");
-            match io::stdin().read_line(&mut input_buffer) {
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) => {
+                    println!("\nBye!");
+                    break;
+                },
                 Ok(_) => {
-                    if input_buffer.is_empty() {
-                        println!("\nBye!");
-                        break;
+                    if entry.is_empty() && line.trim_start().starts_with(':') {
+                        self.run_command(line.trim());
+                        continue;
+                    }
+                    entry.push_str(&line);
+                    // A blank line gives up on waiting for the brackets to be closed.
+                    if !line.trim().is_empty() && is_incomplete(&entry) {
+                        continue;
                     }
-                    self.run(&input_buffer);
-                    ERROR_REPORTER.lock().unwrap().had_error = false;
+                    self.run_entry(&entry);
+                    entry.clear();
                 },
                 Err(e) => {
                     eprintln!("Error reading input: {}", e);
                     break;
                 },
             }
-            input_buffer.clear();
         }
     }
 
-    fn run(&mut self, source: &str) {
-        let scanner = Scanner::new(source);
-        let tokens = scanner.scan_tokens();
-        let mut parser = Parser::new(tokens);
-        let mut statements = parser.parse();
+    // Commands start with a colon, and take the rest of the line as their argument.
+    fn run_command(&mut self, command: &str) {
+        let (name, argument) = match command.find(char::is_whitespace) {
+            Some(end) => (&command[..end], command[end..].trim()),
+            None => (command, ""),
+        };
+        match name {
+            ":help" => println!("{}", REPL_HELP),
+            ":env" => {
+                let mut globals = self.backend.globals();
+                globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+                for (name, value) in globals {
+                    println!("{} = {:?}", name, value);
+                }
+            },
+            ":load" => match fs::read_to_string(argument) {
+                Ok(source) => {
+                    if let Err(error) = self.evaluate_file(Path::new(argument), &source) {
+                        eprintln!("{}", self.render(&error));
+                    }
+                },
+                Err(e) => eprintln!("Failed to open source file {}: {}", argument, e),
+            },
+            ":reset" => self.reset(),
+            ":ast" => {
+                let (tokens, mut diagnostics) = Scanner::new(new_source("<repl>", argument)).scan_tokens();
+                let (expression, parse_diagnostics) = Parser::new(tokens).parse_expression();
+                diagnostics.extend(parse_diagnostics);
+                match expression {
+                    Some(expression) if diagnostics.is_empty() => println!("{}", expression),
+                    _ => eprintln!("{}", self.render(&LoxError::Compile(diagnostics))),
+                }
+            },
+            ":tokens" => match self.dump("<repl>", argument, Dump::Tokens) {
+                Ok(tokens) => print!("{}", tokens),
+                Err(error) => eprintln!("{}", self.render(&error)),
+            },
+            ":time" => {
+                let start = Instant::now();
+                self.run_entry(argument);
+                println!("Took {:?}.", start.elapsed());
+            },
+            _ => eprintln!("Unknown command '{}'. Type :help for a list of commands.", name),
+        }
+    }
 
-        if ERROR_REPORTER.lock().unwrap().had_error {
-            return
+    fn run_entry(&mut self, entry: &str) {
+        let result = self.compile("<repl>", Path::new(""), entry, true).and_then(|statements| {
+            let is_expression = matches!(statements.last(), Some(Statement::Expression{..}));
+            self.backend.run(statements).map(|value| if is_expression { value } else { Value::Nil })
+        });
+        match result {
+            // Like Python, nothing is shown for nil, so calls made for their side effects stay quiet.
+            Ok(Value::Nil) => {},
+            Ok(value) => println!("{:?}", value),
+            Err(error) => eprintln!("{}", self.render(&error)),
         }
+    }
 
-        let mut scopes = Vec::new();
-        let function_type = FunctionType::None;
-        let class_type = ClassType::None;
+    // Runs a piece of source, returning the value of its last statement if that's an expression, or
+    // nil otherwise. Globals defined by one call are visible to the next. Imports are found relative
+    // to the working directory.
+    pub fn evaluate(&mut self, source: &str) -> Result<Value, LoxError> {
+        let statements = self.compile("<script>", Path::new(""), source, false)?;
+        self.backend.run(statements)
+    }
+
+    // Like evaluate, for the contents of a file. Imports are found relative to it, and diagnostics
+    // are shown under its path.
+    pub fn evaluate_file(&mut self, path: &Path, source: &str) -> Result<Value, LoxError> {
+        let name = path.display().to_string();
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        // Any module importing the file back is then caught as a cycle.
+        let canonical = path.canonicalize().ok();
+        if let Some(canonical) = &canonical {
+            self.loading.push((canonical.clone(), name.clone()));
+        }
+        let result = self.compile(&name, directory, source, false);
+        if canonical.is_some() {
+            self.loading.pop();
+        }
+        self.backend.run(result?)
+    }
+
+    // Forgets every global and module defined by scripts. Globals defined by the host are kept.
+    pub fn reset(&mut self) {
+        self.backend = self.backend.fresh();
+        for (name, value) in &self.host_globals {
+            self.backend.define_global(name, value.clone());
+        }
+        self.modules.clear();
+    }
+
+    // Runs a script only as far as the given stage, and shows what came out of it, one token or
+    // top-level statement per line. Imports aren't loaded.
+    pub fn dump(&mut self, name: &str, source: &str, dump: Dump) -> Result<String, LoxError> {
+        let lines: Vec<String> = match dump {
+            Dump::Tokens => {
+                let (tokens, diagnostics) = Scanner::new(new_source(name, source)).scan_tokens();
+                if !diagnostics.is_empty() {
+                    return Err(LoxError::Compile(diagnostics));
+                }
+                tokens.iter().map(|token| format!("{}:{} {}", token.line(), token.span().column, token)).collect()
+            },
+            Dump::Ast => self.parse(name, source, false)?.iter().map(Statement::to_string).collect(),
+            Dump::Resolved => {
+                let mut statements = self.parse(name, source, false)?;
+                resolve(&mut statements)?;
+                statements.iter().map(Statement::to_string).collect()
+            },
+        };
+        Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+    }
+
+    // Formats an error the way the command line tool does, quoting the source it happened in.
+    pub fn render(&self, error: &LoxError) -> String {
+        error.render()
+    }
+
+    // Scans, parses and resolves a piece of source, and loads the modules it imports. Nothing in the
+    // source itself is run. REPL entries can end in an expression without a semicolon.
+    fn compile(&mut self, name: &str, directory: &Path, text: &str, is_repl_entry: bool) -> Result<Vec<Statement>, LoxError> {
+        let mut statements = self.parse(name, text, is_repl_entry)?;
+        resolve(&mut statements)?;
         for statement in &mut statements {
-            statement.resolve(&mut scopes, &function_type, &class_type);
+            if let Statement::Import{keyword: _, path, names, bindings} = statement {
+                *bindings = self.import(directory, path, names)?;
+            }
         }
-        if ERROR_REPORTER.lock().unwrap().had_error {
-            return
+        Ok(statements)
+    }
+
+    fn parse(&mut self, name: &str, text: &str, is_repl_entry: bool) -> Result<Vec<Statement>, LoxError> {
+        let scanner = Scanner::new(new_source(name, text));
+        let (tokens, mut diagnostics) = scanner.scan_tokens();
+        let parser = if is_repl_entry { Parser::for_repl(tokens) } else { Parser::new(tokens) };
+        let (statements, parse_diagnostics) = parser.parse();
+        diagnostics.extend(parse_diagnostics);
+        if !diagnostics.is_empty() {
+            return Err(LoxError::Compile(diagnostics));
+        }
+        Ok(statements)
+    }
+
+    // Finds the values an import statement binds, running the module if it hasn't been already.
+    fn import(&mut self, directory: &Path, path: &Token, names: &[Token]) -> Result<Vec<(String, Value)>, LoxError> {
+        let relative = match path.token_type() {
+            token::Type::String(relative) => relative,
+            _ => panic!("A non-string import path snuck in!"),
+        };
+        let error = |message: String| LoxError::Compile(vec![Diagnostic::on_token(path, &message)]);
+        let name = directory.join(relative).display().to_string();
+        let canonical = directory.join(relative).canonicalize().map_err(|e| error(format!("Couldn't import '{}': {}.", relative, e)))?;
+        if let Some(start) = self.loading.iter().position(|(loading, _)| *loading == canonical) {
+            let mut cycle: Vec<&str> = self.loading[start..].iter().map(|(_, name)| name.as_str()).collect();
+            cycle.push(&self.loading[start].1);
+            return Err(error(format!("Import cycle: {}.", cycle.join(" -> "))));
+        }
+
+        let exports = match self.modules.get(&canonical) {
+            Some(exports) => exports.clone(),
+            None => {
+                let text = fs::read_to_string(&canonical).map_err(|e| error(format!("Couldn't import '{}': {}.", relative, e)))?;
+                self.loading.push((canonical.clone(), name.clone()));
+                let result = self.load(&name, canonical.parent().unwrap_or(directory), &text);
+                self.loading.pop();
+                let importer = path.span().source.map_or_else(String::new, |source| source.name.clone());
+                let exports = result.map_err(|error| error.with_note(format!("note: imported from {}:{}", importer, path.line())))?;
+                self.modules.insert(canonical, exports.clone());
+                exports
+            },
+        };
+        if names.is_empty() {
+            return Ok(exports);
+        }
+        names.iter().map(|wanted| {
+            exports.iter().find(|(export, _)| export == wanted.lexeme()).cloned().ok_or_else(|| {
+                LoxError::Compile(vec![Diagnostic::on_token(wanted, &format!("Module '{}' has no '{}'.", relative, wanted.lexeme()))])
+            })
+        }).collect()
+    }
+
+    // Runs a module in globals of its own, and returns what it declared at the top level.
+    fn load(&mut self, name: &str, directory: &Path, text: &str) -> Result<Vec<(String, Value)>, LoxError> {
+        let statements = self.compile(name, directory, text, false)?;
+        let declared: Vec<String> = statements.iter().filter_map(|statement| match statement {
+            Statement::Var{name, initializer: _} => Some(name.lexeme().to_string()),
+            Statement::Function{name, params: _, body: _} => Some(name.lexeme().to_string()),
+            Statement::Class{name, superclass: _, methods: _} => Some(name.lexeme().to_string()),
+            _ => None,
+        }).collect();
+        let mut backend = self.backend.fresh();
+        for (name, value) in &self.host_globals {
+            backend.define_global(name, value.clone());
+        }
+        backend.run(statements)?;
+        Ok(declared.into_iter().filter_map(|name| backend.get_global(&name).map(|value| (name, value))).collect())
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.backend.get_global(name)
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.host_globals.insert(name.to_string(), value.clone());
+        self.backend.define_global(name, value);
+    }
+
+    // Makes a Rust function callable from scripts as a global. Arguments are counted before the
+    // function is called, but it's up to the function to check their types.
+    pub fn define_native<F>(&mut self, name: &str, arity: Arity, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
+        self.define_global(name, Value::Callable(Rc::new(NativeFunction::new(name, arity, function))));
+    }
+}
+
+// Source about to be scanned. It's kept for as long as any token, and so anything built from one,
+// might still quote it.
+fn new_source(name: &str, text: &str) -> Rc<Source> {
+    Rc::new(Source{name: name.to_string(), text: text.to_string()})
+}
+
+fn resolve(statements: &mut [Statement]) -> Result<(), LoxError> {
+    let mut scopes = Vec::new();
+    let mut diagnostics = Vec::new();
+    for statement in statements {
+        statement.resolve(&mut scopes, &FunctionType::None, &ClassType::None, &LoopType::None, &mut diagnostics);
+    }
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(LoxError::Compile(diagnostics))
+    }
+}
+
+// Whether a REPL entry has brackets or a string left open, and so is expected to continue on the
+// next line. Brackets inside strings and comments are skipped over by the scanner.
+fn is_incomplete(entry: &str) -> bool {
+    let (tokens, diagnostics) = Scanner::new(new_source("<repl>", entry)).scan_tokens();
+    if diagnostics.iter().any(|diagnostic| diagnostic.message == "Unterminated string.") {
+        return true;
+    }
+    let mut depth = 0;
+    for token in &tokens {
+        match token.token_type() {
+            token::Type::LeftParen | token::Type::LeftBrace | token::Type::LeftBracket => depth += 1,
+            token::Type::RightParen | token::Type::RightBrace | token::Type::RightBracket => depth -= 1,
+            _ => {},
         }
-        self.interpreter.interpret(statements);
     }
+    depth > 0
 }
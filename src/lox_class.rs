@@ -1,39 +1,73 @@
 use std::{cell::RefCell, fmt, rc::Rc, collections::HashMap};
 
-use crate::{callable::{Callable, LoxCallable}, environment::Environment, error_reporter::ERROR_REPORTER, expression::Value, token::Token};
+use crate::{callable::{Arity, Callable, Kind, Method}, diagnostic::Diagnostic, expression::Value, gc, gc::{Trace, Tracer}, token::Token};
 
 pub struct LoxClass {
     name: String,
-    methods: HashMap<String, Rc<LoxCallable>>,
+    superclass: Option<Rc<LoxClass>>,
+    methods: HashMap<String, Rc<dyn Method>>,
 }
 
 impl LoxClass {
-    pub fn new(name: String, methods: HashMap<String, Rc<LoxCallable>>) -> Self {
+    pub fn new(name: String, superclass: Option<Rc<LoxClass>>, methods: HashMap<String, Rc<dyn Method>>) -> Self {
         Self {
             name,
+            superclass,
             methods,
         }
     }
 
-    pub fn find_method(&self, name: &str) -> Option<&Rc<LoxCallable>> {
-        self.methods.get(name)
+    pub fn find_method(&self, name: &str) -> Option<&Rc<dyn Method>> {
+        if let Some(method) = self.methods.get(name) {
+            Some(method)
+        } else if let Some(superclass) = &self.superclass {
+            superclass.find_method(name)
+        } else {
+            None
+        }
     }
 }
 
 impl Callable for LoxClass {
-    fn call(self: Rc<Self>, environment: Rc<RefCell<Environment>>, arguments: Vec<Value>) -> Option<Value> {
-        let instance = Rc::new(RefCell::new(LoxInstance::new(self.clone())));
+    fn call(self: Rc<Self>, arguments: Vec<Value>) -> Result<Value, Diagnostic> {
+        let instance = gc::new_instance(LoxInstance::new(self.clone()));
         if let Some(init) = self.find_method("init") {
-            Rc::new(init.bind(instance.clone())).call(environment, arguments);
+            init.bind(instance.clone()).call(arguments)?;
         }
-        Some(Value::Instance(instance))
+        Ok(Value::Instance(instance))
     }
 
-    fn arity(&self) -> usize {
+    fn arity(&self) -> Arity {
         if let Some(init) = self.find_method("init") {
             init.arity()
         } else {
-            0
+            Arity::Exactly(0)
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Class
+    }
+
+    fn as_class(self: Rc<Self>) -> Option<Rc<LoxClass>> {
+        Some(self)
+    }
+
+    // Errors can only happen inside the initializer.
+    fn frame_name(&self) -> Option<&str> {
+        Some("init")
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(superclass) = &self.superclass {
+            tracer.pointer(superclass);
+        }
+        for method in self.methods.values() {
+            tracer.pointer(method);
         }
     }
 }
@@ -57,19 +91,39 @@ impl LoxInstance {
         }
     }
 
-    pub fn get(&self, name: &Token, this_instance: Rc<RefCell<LoxInstance>>) -> Option<Value> {
+    pub fn get(&self, name: &Token, this_instance: Rc<RefCell<LoxInstance>>) -> Result<Value, String> {
         if let Some(value) = self.fields.get(name.lexeme()) {
-            Some(value.clone())
+            Ok(value.clone())
         } else if let Some(method) = self.class.find_method(name.lexeme()) {
-            Some(Value::Callable(Rc::new(method.clone().bind(this_instance))))
+            Ok(Value::Callable(method.bind(this_instance)))
         } else {
-            ERROR_REPORTER.lock().unwrap().error_on_token(name, &format!("Undefined property {}.", name.lexeme()));
-            None
+            Err(format!("Undefined property '{}'.", name.lexeme()))
         }
     }
 
     pub fn set(&mut self, name: &Token, value: Value) {
-        self.fields.insert(name.lexeme().to_string(), value);
+        self.set_field(name.lexeme(), value);
+    }
+
+    pub fn field(&self, name: &str) -> Option<Value> {
+        self.fields.get(name).cloned()
+    }
+
+    pub fn set_field(&mut self, name: &str, value: Value) {
+        self.fields.insert(name.to_string(), value);
+    }
+
+    pub fn clear(&mut self) {
+        self.fields.clear();
+    }
+}
+
+impl Trace for LoxInstance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.pointer(&self.class);
+        for value in self.fields.values() {
+            value.trace(tracer);
+        }
     }
 }
 
@@ -0,0 +1,74 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use crate::callable::NativeMethod;
+use crate::expression::Value;
+use crate::gc;
+use crate::token::Token;
+use crate::util;
+
+type ListMethod = fn(&Rc<RefCell<Vec<Value>>>, Vec<Value>) -> Result<Value, String>;
+
+pub fn get_method(list: &Rc<RefCell<Vec<Value>>>, name: &Token) -> Result<Value, String> {
+    let (arity, function): (usize, ListMethod) = match name.lexeme() {
+        "len" => (0, len),
+        "push" => (1, push),
+        "pop" => (0, pop),
+        "insert" => (2, insert),
+        "remove" => (1, remove),
+        _ => return Err(format!("Undefined property '{}'.", name.lexeme())),
+    };
+    Ok(Value::Callable(gc::new_callable(NativeMethod::new(name.clone(), list.clone(), arity, function))))
+}
+
+// Converts a Lox value into an index into a list of the given length. Indexes one past the end
+// are only valid when inserting.
+pub fn index(value: &Value, len: usize, allow_end: bool) -> Result<usize, String> {
+    match value {
+        Value::Number(n) if n.fract() == 0.0 => {
+            if *n >= 0.0 && (*n < len as f64 || (allow_end && *n == len as f64)) {
+                Ok(*n as usize)
+            } else {
+                Err(format!("List index {} out of range for list of length {}.", n, len))
+            }
+        },
+        _ => Err("List index must be an integer.".to_string()),
+    }
+}
+
+pub fn fmt_list(list: &Rc<RefCell<Vec<Value>>>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    util::fmt_once(Rc::as_ptr(list), "[...]", f, |f| {
+        write!(f, "[")?;
+        for (i, value) in list.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        write!(f, "]")
+    })
+}
+
+fn len(list: &Rc<RefCell<Vec<Value>>>, _: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Number(list.borrow().len() as f64))
+}
+
+fn push(list: &Rc<RefCell<Vec<Value>>>, mut arguments: Vec<Value>) -> Result<Value, String> {
+    list.borrow_mut().push(arguments.remove(0));
+    Ok(Value::Nil)
+}
+
+fn pop(list: &Rc<RefCell<Vec<Value>>>, _: Vec<Value>) -> Result<Value, String> {
+    list.borrow_mut().pop().ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+fn insert(list: &Rc<RefCell<Vec<Value>>>, mut arguments: Vec<Value>) -> Result<Value, String> {
+    let value = arguments.remove(1);
+    let i = index(&arguments[0], list.borrow().len(), true)?;
+    list.borrow_mut().insert(i, value);
+    Ok(Value::Nil)
+}
+
+fn remove(list: &Rc<RefCell<Vec<Value>>>, arguments: Vec<Value>) -> Result<Value, String> {
+    let i = index(&arguments[0], list.borrow().len(), false)?;
+    Ok(list.borrow_mut().remove(i))
+}
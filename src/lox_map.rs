@@ -0,0 +1,177 @@
+use std::{cell::RefCell, collections::HashMap, fmt, hash::{Hash, Hasher}, mem, rc::Rc};
+
+use crate::callable::NativeMethod;
+use crate::expression::Value;
+use crate::gc;
+use crate::gc::{Trace, Tracer};
+use crate::token::Token;
+use crate::util;
+
+// A value that can be used as a map key. Only strings, numbers, booleans and nil are allowed, and
+// equality follows the PartialEq implementation for Value. Numbers are hashed by their bits, with
+// -0 folded into 0 so that keys which compare equal also hash equally. NaN is rejected outright,
+// since it isn't equal to itself and so could never be looked up again.
+#[derive(Clone)]
+pub struct MapKey(Value);
+
+impl MapKey {
+    pub fn new(value: Value) -> Result<Self, String> {
+        match value {
+            Value::Number(n) if n.is_nan() => Err("Map keys can't be NaN.".to_string()),
+            Value::String(_) | Value::Number(_) | Value::True | Value::False | Value::Nil => Ok(Self(value)),
+            _ => Err("Map keys must be strings, numbers, booleans or nil.".to_string()),
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(&self.0).hash(state);
+        match &self.0 {
+            Value::String(s) => s.hash(state),
+            Value::Number(n) => if *n == 0.0 { 0.0f64.to_bits().hash(state) } else { n.to_bits().hash(state) },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Entries are kept in insertion order, so that printing a map (or asking for its keys) gives the
+// same answer every time. Removing an entry leaves a gap in its place rather than shifting the rest
+// down, and the gaps are only closed up once they make up half of the entries.
+pub struct LoxMap {
+    indices: HashMap<MapKey, usize>,
+    entries: Vec<Option<(MapKey, Value)>>,
+}
+
+impl LoxMap {
+    pub fn new() -> Self {
+        Self {
+            indices: HashMap::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.indices.get(key).and_then(|&i| self.entries[i].as_ref()).map(|(_, value)| value)
+    }
+
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        if let Some(&i) = self.indices.get(&key) {
+            self.entries[i] = Some((key, value));
+        } else {
+            self.indices.insert(key.clone(), self.entries.len());
+            self.entries.push(Some((key, value)));
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let removed = self.indices.remove(key)?;
+        let (_, value) = self.entries[removed].take()?;
+        if self.indices.len() <= self.entries.len() / 2 {
+            self.compact();
+        }
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &(MapKey, Value)> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn clear(&mut self) {
+        self.indices.clear();
+        self.entries.clear();
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(Option::is_some);
+        for (i, (key, _)) in self.entries.iter().flatten().enumerate() {
+            self.indices.insert(key.clone(), i);
+        }
+    }
+}
+
+impl Trace for LoxMap {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (_, value) in self.entries() {
+            value.trace(tracer);
+        }
+    }
+}
+
+// String keys are quoted, so that `{"1": 1}` and `{1: 1}` can be told apart.
+pub fn fmt_map(map: &Rc<RefCell<LoxMap>>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    util::fmt_once(Rc::as_ptr(map), "{...}", f, |f| {
+        write!(f, "{{")?;
+        for (i, (key, value)) in map.borrow().entries().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match key.value() {
+                Value::String(s) => write!(f, "\"{}\": {}", s, value)?,
+                key => write!(f, "{}: {}", key, value)?,
+            }
+        }
+        write!(f, "}}")
+    })
+}
+
+type MapMethod = fn(&Rc<RefCell<LoxMap>>, Vec<Value>) -> Result<Value, String>;
+
+pub fn get_method(map: &Rc<RefCell<LoxMap>>, name: &Token) -> Result<Value, String> {
+    let (arity, function): (usize, MapMethod) = match name.lexeme() {
+        "len" => (0, len),
+        "keys" => (0, keys),
+        "values" => (0, values),
+        "has" => (1, has),
+        "remove" => (1, remove),
+        _ => return Err(format!("Undefined property '{}'.", name.lexeme())),
+    };
+    Ok(Value::Callable(gc::new_callable(NativeMethod::new(name.clone(), map.clone(), arity, function))))
+}
+
+fn len(map: &Rc<RefCell<LoxMap>>, _: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Number(map.borrow().len() as f64))
+}
+
+fn keys(map: &Rc<RefCell<LoxMap>>, _: Vec<Value>) -> Result<Value, String> {
+    let keys = map.borrow().entries().map(|(key, _)| key.value().clone()).collect();
+    Ok(Value::List(gc::new_list(keys)))
+}
+
+fn values(map: &Rc<RefCell<LoxMap>>, _: Vec<Value>) -> Result<Value, String> {
+    let values = map.borrow().entries().map(|(_, value)| value.clone()).collect();
+    Ok(Value::List(gc::new_list(values)))
+}
+
+fn has(map: &Rc<RefCell<LoxMap>>, mut arguments: Vec<Value>) -> Result<Value, String> {
+    let key = MapKey::new(arguments.remove(0))?;
+    Ok(Value::from_bool(map.borrow().get(&key).is_some()))
+}
+
+fn remove(map: &Rc<RefCell<LoxMap>>, mut arguments: Vec<Value>) -> Result<Value, String> {
+    let key = MapKey::new(arguments.remove(0))?;
+    let removed = map.borrow_mut().remove(&key);
+    removed.ok_or_else(|| format!("Key '{}' not found in map.", key))
+}
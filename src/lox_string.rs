@@ -0,0 +1,116 @@
+use std::rc::Rc;
+
+use crate::callable::NativeMethod;
+use crate::expression::Value;
+use crate::gc;
+use crate::token::Token;
+
+// Lengths and indexes count chars, as the scanner does, so a string's length is the number of
+// characters written between its quotes.
+
+type StringMethod = fn(&Rc<str>, Vec<Value>) -> Result<Value, String>;
+
+pub fn get_method(string: &str, name: &Token) -> Result<Value, String> {
+    let (arity, function): (usize, StringMethod) = match name.lexeme() {
+        "len" => (0, len),
+        "upper" => (0, upper),
+        "lower" => (0, lower),
+        "trim" => (0, trim),
+        "split" => (1, split),
+        "contains" => (1, contains),
+        "startsWith" => (1, starts_with),
+        "endsWith" => (1, ends_with),
+        "indexOf" => (1, index_of),
+        "replace" => (2, replace),
+        "slice" => (2, slice),
+        "chars" => (0, chars),
+        _ => return Err(format!("Undefined property '{}'.", name.lexeme())),
+    };
+    Ok(Value::Callable(gc::new_callable(NativeMethod::new(name.clone(), Rc::from(string), arity, function))))
+}
+
+fn argument(value: &Value) -> Result<&str, String> {
+    value.expect_string().map_err(|error| error.to_string())
+}
+
+// Converts a Lox value into a char index into a string of the given length. Slices can end one past
+// the last char.
+fn index(value: &Value, len: usize) -> Result<usize, String> {
+    match value {
+        Value::Number(n) if n.fract() == 0.0 => {
+            if *n >= 0.0 && *n <= len as f64 {
+                Ok(*n as usize)
+            } else {
+                Err(format!("String index {} out of range for string of length {}.", n, len))
+            }
+        },
+        _ => Err("String index must be an integer.".to_string()),
+    }
+}
+
+fn len(string: &Rc<str>, _: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Number(string.chars().count() as f64))
+}
+
+fn upper(string: &Rc<str>, _: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::String(string.to_uppercase()))
+}
+
+fn lower(string: &Rc<str>, _: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::String(string.to_lowercase()))
+}
+
+fn trim(string: &Rc<str>, _: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::String(string.trim().to_string()))
+}
+
+fn split(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    let separator = argument(&arguments[0])?;
+    if separator.is_empty() {
+        return Err("Can't split on an empty string; use chars() instead.".to_string());
+    }
+    let parts = string.split(separator).map(Value::from).collect();
+    Ok(Value::List(gc::new_list(parts)))
+}
+
+fn contains(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from_bool(string.contains(argument(&arguments[0])?)))
+}
+
+fn starts_with(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from_bool(string.starts_with(argument(&arguments[0])?)))
+}
+
+fn ends_with(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from_bool(string.ends_with(argument(&arguments[0])?)))
+}
+
+// The char index of the first occurrence, or -1 if there isn't one.
+fn index_of(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    let index = match string.find(argument(&arguments[0])?) {
+        Some(byte) => string[..byte].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+// Replaces every occurrence.
+fn replace(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::String(string.replace(argument(&arguments[0])?, argument(&arguments[1])?)))
+}
+
+// The chars from the start index up to, but not including, the end index.
+fn slice(string: &Rc<str>, arguments: Vec<Value>) -> Result<Value, String> {
+    let len = string.chars().count();
+    let start = index(&arguments[0], len)?;
+    let end = index(&arguments[1], len)?;
+    if start > end {
+        return Err(format!("Slice start {} is after its end {}.", start, end));
+    }
+    Ok(Value::String(string.chars().skip(start).take(end - start).collect()))
+}
+
+fn chars(string: &Rc<str>, _: Vec<Value>) -> Result<Value, String> {
+    let chars = string.chars().map(|c| Value::String(c.to_string())).collect();
+    Ok(Value::List(gc::new_list(chars)))
+}
@@ -1,37 +1,130 @@
-#[macro_use]
-extern crate lazy_static;
-
-mod token;
-mod scanner;
-mod lox;
-mod util;
-mod error_reporter;
-mod expression;
-mod parser;
-mod interpreter;
-mod statement;
-mod environment;
-mod callable;
-mod lox_class;
-
 use std::env;
+use std::fs;
+use std::path::Path;
 use std::process;
+use std::thread;
+
+use lox_rs::{gc, Backend, Dump, Interpreter, Lox, LoxError, Vm};
+use lox_rs::conformance::{Outcome, Runner};
 
-use lox::Lox;
-use util::EX_USAGE;
+const EX_TESTS_FAILED: i32 = 1;
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_SOFTWARE: i32 = 70;
+
+// The tree-walker recurses on the native stack for each Lox call, and unoptimised builds use a lot
+// of it per call, so programs are run on a thread with room for the deepest recursion allowed.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
 
 fn main() {
-    let mut argv = env::args().skip(1);
-    let mut lox = Lox::new();
-    if let Some(argument) = argv.next() {
-        if let Some(_) = argv.next() {
-            println!("Usage: lox-rs [script]");
-            process::exit(EX_USAGE);
+    let interpreter = thread::Builder::new().stack_size(STACK_SIZE).spawn(run).expect("Failed to start the interpreter thread");
+    if interpreter.join().is_err() {
+        process::exit(EX_SOFTWARE);
+    }
+}
+
+fn run() {
+    if env::args().nth(1).as_deref() == Some("test") {
+        run_tests(env::args().skip(2).collect());
+    }
+    let mut use_vm = false;
+    let mut print_gc_stats = false;
+    let mut dumps = Vec::new();
+    let mut script = None;
+    for argument in env::args().skip(1) {
+        if argument == "--vm" {
+            use_vm = true;
+        } else if argument == "--gc-stress" {
+            gc::set_stress_mode(true);
+        } else if argument == "--gc-stats" {
+            print_gc_stats = true;
+        } else if argument == "--dump-tokens" {
+            dumps.push(Dump::Tokens);
+        } else if argument == "--dump-ast" {
+            dumps.push(Dump::Ast);
+        } else if argument == "--dump-resolved" {
+            dumps.push(Dump::Resolved);
+        } else if script.is_none() && !argument.starts_with("--") {
+            script = Some(argument);
         } else {
-            lox.run_file(&argument);
+            usage();
+        }
+    }
+    if !dumps.is_empty() && script.is_none() {
+        usage();
+    }
+    let backend = if use_vm { Backend::Vm(Vm::new()) } else { Backend::TreeWalker(Interpreter::new()) };
+    let mut lox = Lox::new(backend);
+    let result = if let Some(path) = script {
+        let source = fs::read_to_string(&path).unwrap_or_else(|error| {
+            eprintln!("Failed to open source file {}: {}", path, error);
+            process::exit(EX_NOINPUT);
+        });
+        // Dumping stops short of running the script.
+        let mut result = Ok(());
+        if dumps.is_empty() {
+            result = lox.evaluate_file(Path::new(&path), &source).map(|_| ());
+        }
+        for dump in dumps {
+            match lox.dump(&path, &source, dump) {
+                Ok(output) => print!("{}", output),
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                },
+            }
         }
+        result.map_err(|error| (lox.render(&error), error))
     } else {
         lox.run_prompt();
+        Ok(())
+    };
+    if print_gc_stats {
+        gc::collect();
+        let stats = gc::stats();
+        eprintln!("[gc] {} allocations, {} collections, {} reclaimed, {} live", stats.allocations, stats.collections, stats.reclaimed, stats.live);
+    }
+    if let Err((rendered, error)) = result {
+        eprintln!("{}", rendered);
+        process::exit(match error {
+            LoxError::Compile(_) => EX_DATAERR,
+            LoxError::Runtime(_) => EX_SOFTWARE,
+        });
     }
 }
 
+fn usage() -> ! {
+    println!("Usage: lox-rs [--vm] [--gc-stress] [--gc-stats] [script]");
+    println!("       lox-rs [--dump-tokens] [--dump-ast] [--dump-resolved] <script>");
+    println!("       lox-rs test [--vm] [--gc-stress] <directory>");
+    process::exit(EX_USAGE);
+}
+
+// `lox-rs test [--vm] [--gc-stress] <directory>` runs the annotated scripts in a directory, such as
+// the Crafting Interpreters test suite, with this interpreter. The flags are passed on to it.
+fn run_tests(arguments: Vec<String>) -> ! {
+    let (flags, directories): (Vec<String>, Vec<String>) = arguments.into_iter().partition(|argument| argument.starts_with("--"));
+    if directories.len() != 1 || flags.iter().any(|flag| flag != "--vm" && flag != "--gc-stress") {
+        println!("Usage: lox-rs test [--vm] [--gc-stress] <directory>");
+        process::exit(EX_USAGE);
+    }
+    let interpreter = env::current_exe().unwrap_or_else(|error| {
+        eprintln!("Failed to find the interpreter: {}", error);
+        process::exit(EX_SOFTWARE);
+    });
+    let runner = Runner::new(interpreter, flags);
+    let summary = runner.run_directory(Path::new(&directories[0]), |path, outcome| {
+        if let Outcome::Fail(failures) = outcome {
+            println!("FAIL {}", path.display());
+            for failure in failures {
+                println!("     {}", failure);
+            }
+        }
+    }).unwrap_or_else(|error| {
+        eprintln!("Failed to run tests in {}: {}", directories[0], error);
+        process::exit(EX_NOINPUT);
+    });
+    println!("Passed {}, failed {}, skipped {}.", summary.passed, summary.failures.len(), summary.skipped);
+    process::exit(if summary.is_success() { 0 } else { EX_TESTS_FAILED });
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::f64::consts;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::callable::{Arity, NativeFunction};
+use crate::expression::Value;
+use crate::diagnostic::RuntimeError;
+use crate::lox_class::{LoxClass, LoxInstance};
+use crate::gc;
+
+// The native functions every program starts out with, shared by both backends.
+pub fn globals() -> Vec<(&'static str, Value)> {
+    vec![
+        ("clock", native("clock", Arity::Exactly(0), clock)),
+        ("Math", math()),
+    ]
+}
+
+// The maths functions and constants, as fields of a single `Math` instance so that they don't take
+// up any more global names, e.g. `Math.sqrt(2)` and `Math.PI`.
+fn math() -> Value {
+    let mut math = LoxInstance::new(gc::new_callable(LoxClass::new("Math".to_string(), None, HashMap::new())));
+    let members = [
+        ("floor", unary("Math.floor", f64::floor)),
+        ("ceil", unary("Math.ceil", f64::ceil)),
+        ("round", unary("Math.round", f64::round)),
+        ("abs", unary("Math.abs", f64::abs)),
+        ("sqrt", unary("Math.sqrt", f64::sqrt)),
+        ("sin", unary("Math.sin", f64::sin)),
+        ("cos", unary("Math.cos", f64::cos)),
+        ("tan", unary("Math.tan", f64::tan)),
+        ("log", unary("Math.log", f64::ln)),
+        ("exp", unary("Math.exp", f64::exp)),
+        ("pow", binary("Math.pow", f64::powf)),
+        ("atan2", binary("Math.atan2", f64::atan2)),
+        ("min", native("Math.min", Arity::AtLeast(1), |arguments| fold(arguments, f64::min))),
+        ("max", native("Math.max", Arity::AtLeast(1), |arguments| fold(arguments, f64::max))),
+        ("isNan", native("Math.isNan", Arity::Exactly(1), |arguments| Ok(Value::from(arguments[0].expect_number()?.is_nan())))),
+        ("isFinite", native("Math.isFinite", Arity::Exactly(1), |arguments| Ok(Value::from(arguments[0].expect_number()?.is_finite())))),
+        ("PI", Value::Number(consts::PI)),
+        ("E", Value::Number(consts::E)),
+        ("INFINITY", Value::Number(f64::INFINITY)),
+        ("NAN", Value::Number(f64::NAN)),
+    ];
+    for (name, value) in members {
+        math.set_field(name, value);
+    }
+    Value::Instance(gc::new_instance(math))
+}
+
+fn native(name: &str, arity: Arity, function: fn(&[Value]) -> Result<Value, RuntimeError>) -> Value {
+    Value::Callable(Rc::new(NativeFunction::new(name, arity, function)))
+}
+
+fn unary(name: &str, function: fn(f64) -> f64) -> Value {
+    Value::Callable(Rc::new(NativeFunction::new(name, Arity::Exactly(1), move |arguments| {
+        Ok(Value::Number(function(arguments[0].expect_number()?)))
+    })))
+}
+
+fn binary(name: &str, function: fn(f64, f64) -> f64) -> Value {
+    Value::Callable(Rc::new(NativeFunction::new(name, Arity::Exactly(2), move |arguments| {
+        Ok(Value::Number(function(arguments[0].expect_number()?, arguments[1].expect_number()?)))
+    })))
+}
+
+// Folds the numbers with f64::min or f64::max. Those skip over NaN, but here any NaN makes the
+// result NaN.
+fn fold(arguments: &[Value], function: fn(f64, f64) -> f64) -> Result<Value, RuntimeError> {
+    let mut result = arguments[0].expect_number()?;
+    for argument in &arguments[1..] {
+        let number = argument.expect_number()?;
+        result = if result.is_nan() || number.is_nan() { f64::NAN } else { function(result, number) };
+    }
+    Ok(Value::Number(result))
+}
+
+fn clock(_: &[Value]) -> Result<Value, RuntimeError> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => Ok(Value::Number(time.as_millis() as f64 / 1000.0)),
+        Err(_) => Err(RuntimeError::new("Unable to determine offset from UNIX epoch: Time is going backwards!")),
+    }
+}
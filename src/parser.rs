@@ -6,11 +6,14 @@ use crate::expression::Value;
 use crate::expression::Expression;
 use crate::statement::Statement;
 use crate::util::MAXIMUM_PARAMETER_COUNT;
-use crate::error_reporter::ERROR_REPORTER;
+use crate::diagnostic::Diagnostic;
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    diagnostics: Vec<Diagnostic>,
+    // Whether the last statement can be an expression without a semicolon, as typed into the REPL.
+    bare_expressions: bool,
 }
 
 impl Parser {
@@ -18,30 +21,84 @@ impl Parser {
         Self {
             tokens,
             current: 0,
+            diagnostics: Vec::new(),
+            bare_expressions: false,
         }
     }
 
-    pub fn parse(&mut self) -> Vec<Statement> {
+    pub fn for_repl(tokens: Vec<Token>) -> Self {
+        Self {
+            bare_expressions: true,
+            ..Self::new(tokens)
+        }
+    }
+
+    pub fn parse(mut self) -> (Vec<Statement>, Vec<Diagnostic>) {
         let mut statements = Vec::new();
         while !self.is_at_end() {
-            if let Some(statement) = self.declaration() {
+            let statement = if self.match_types(&[token::Type::Import]) {
+                self.import_statement().or_else(|| {
+                    self.synchronize();
+                    None
+                })
+            } else {
+                self.declaration()
+            };
+            if let Some(statement) = statement {
                statements.push(statement);
             }
         }
-        statements
+        (statements, self.diagnostics)
+    }
+
+    // Parses the tokens as a lone expression, with nothing after it.
+    pub fn parse_expression(mut self) -> (Option<Expression>, Vec<Diagnostic>) {
+        let expression = self.expression();
+        if expression.is_some() && !self.is_at_end() {
+            self.error(Diagnostic::on_token(self.peek(), "Expected end of expression."));
+        }
+        (expression, self.diagnostics)
+    }
+
+    // Either `import "path";`, or `import a, b from "path";` to only take some of its names. "from"
+    // isn't a keyword, so that it can still be used as a name everywhere else.
+    fn import_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
+        let mut names = Vec::new();
+        if self.check(&token::Type::Identifier) {
+            loop {
+                names.push(self.consume(&token::Type::Identifier, "Expected name to import.")?.clone());
+                if !self.match_types(&[token::Type::Comma]) { break; }
+            }
+            if self.peek().lexeme() != "from" {
+                self.error(Diagnostic::on_token(self.peek(), "Expected 'from' after imported names."));
+                return None;
+            }
+            self.advance();
+        }
+        let path = self.consume(&token::Type::String(String::new()), "Expected module path string.")?.clone();
+        self.consume(&token::Type::Semicolon, "Expected ';' after import.")?;
+        Some(Statement::Import {
+            keyword,
+            path,
+            names,
+            bindings: Vec::new(),
+        })
     }
 
     fn declaration(&mut self) -> Option<Statement> {
         let statement = if self.match_types(&[token::Type::Class]) {
             self.class_declaration()
-        } else if self.match_types(&[token::Type::Fun]) {
+        } else if self.check(&token::Type::Fun) && self.check_next(&token::Type::Identifier) {
+            // Without a name, it's an anonymous function at the start of an expression statement.
+            self.advance();
             self.function("function")
         } else if self.match_types(&[token::Type::Var]) {
             self.variable_declaration()
         } else {
             self.statement()
         };
-        if let None = statement {
+        if statement.is_none() {
             self.synchronize()
         }
         statement
@@ -49,6 +106,12 @@ impl Parser {
 
     fn class_declaration(&mut self) -> Option<Statement> {
         let name = self.consume(&token::Type::Identifier, "Expected class name.")?.clone();
+        let superclass = if self.match_types(&[token::Type::Less]) {
+            let superclass_name = self.consume(&token::Type::Identifier, "Expected superclass name.")?.clone();
+            Some(Expression::Variable{name: superclass_name, slot: None})
+        } else {
+            None
+        };
         self.consume(&token::Type::LeftBrace, "Expected '{' before class body.")?;
         let mut methods = Vec::new();
         while !self.check(&token::Type::RightBrace) && !self.is_at_end() {
@@ -57,6 +120,7 @@ impl Parser {
         self.consume(&token::Type::RightBrace, "Expected '}' after class body.")?;
         Some(Statement::Class {
             name,
+            superclass,
             methods,
         })
     }
@@ -64,24 +128,30 @@ impl Parser {
     fn function(&mut self, kind: &str) -> Option<Statement> {
         let name = self.consume(&token::Type::Identifier, &format!("Expected {} name.", kind))?.clone();
         self.consume(&token::Type::LeftParen, &format!("Expected '(' after {} name.", kind))?;
+        let parameters = self.parameters()?;
+        self.consume(&token::Type::LeftBrace, &format!("Expected '{{' before {} body.", kind))?;
+        Some(Statement::Function {
+            name,
+            params: parameters,
+            body: self.block_statement()?,
+        })
+    }
+
+    // Everything after the opening paren of a parameter list, up to and including the closing one.
+    fn parameters(&mut self) -> Option<Vec<Token>> {
         let mut parameters = Vec::new();
         if !self.check(&token::Type::RightParen) {
             loop {
                 if parameters.len() >= MAXIMUM_PARAMETER_COUNT {
                     // No need to return None and unwind; the parser is not confused.
-                    ERROR_REPORTER.lock().unwrap().runtime_error_on_token(self.peek(), "Can't have more than 255 parameters.");
+                    self.error(Diagnostic::on_token(self.peek(), "Can't have more than 255 parameters."));
                 }
                 parameters.push(self.consume(&token::Type::Identifier, "Expected parameter name.")?.clone());
                 if !self.match_types(&[token::Type::Comma]) { break; }
             }
         }
         self.consume(&token::Type::RightParen, "Expected ')' after parameters.")?;
-        self.consume(&token::Type::LeftBrace, &format!("Expected '{{' before {} body.", kind))?;
-        Some(Statement::Function {
-            name,
-            params: parameters,
-            body: self.block_statement()?,
-        })
+        Some(parameters)
     }
 
     fn variable_declaration(&mut self) -> Option<Statement> {
@@ -96,7 +166,11 @@ impl Parser {
     }
 
     fn statement(&mut self) -> Option<Statement> {
-        if self.match_types(&[token::Type::For]) {
+        if self.match_types(&[token::Type::Break]) {
+            self.break_statement()
+        } else if self.match_types(&[token::Type::Continue]) {
+            self.continue_statement()
+        } else if self.match_types(&[token::Type::For]) {
             self.for_statement()
         } else if self.match_types(&[token::Type::If]) {
             self.if_statement()
@@ -104,18 +178,39 @@ impl Parser {
             self.print_statement()
         } else if self.match_types(&[token::Type::Return]) {
             self.return_statement()
+        } else if self.match_types(&[token::Type::Import]) {
+            self.error(Diagnostic::on_token(self.previous(), "Imports are only allowed at the top level of a file."));
+            None
+        } else if self.match_types(&[token::Type::Throw]) {
+            self.throw_statement()
+        } else if self.match_types(&[token::Type::Try]) {
+            self.try_statement()
         } else if self.match_types(&[token::Type::While]) {
             self.while_statement()
         } else if self.match_types(&[token::Type::LeftBrace]) {
-            Some(Statement::Block{statements: self.block_statement()?})
+            let brace = self.previous().clone();
+            Some(Statement::Block{brace, statements: self.block_statement()?})
         } else {
             self.expression_statement()
         }
     }
 
+    fn break_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
+        self.consume(&token::Type::Semicolon, "Expected ';' after 'break'.")?;
+        Some(Statement::Break{keyword})
+    }
+
+    fn continue_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
+        self.consume(&token::Type::Semicolon, "Expected ';' after 'continue'.")?;
+        Some(Statement::Continue{keyword})
+    }
+
     // There is no such thing as a for statement! This desugars for-loop syntax into a while loop
-    // inside a block!
+    // inside a block! The increment is kept separate from the body, so that 'continue' still runs it.
     fn for_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
         self.consume(&token::Type::LeftParen, "Expected '(' after 'for'.")?;
         let initializer = if self.match_types(&[token::Type::Semicolon]) {
             None
@@ -127,7 +222,7 @@ impl Parser {
         let condition = if !self.check(&token::Type::Semicolon) {
             self.expression()?
         } else {
-            Expression::Literal{value: Value::True}
+            Expression::Literal{token: keyword.clone(), value: Value::True}
         };
         self.consume(&token::Type::Semicolon, "Expected ';' after loop condition.")?;
         let increment = if !self.check(&token::Type::RightParen) {
@@ -136,15 +231,10 @@ impl Parser {
             None
         };
         self.consume(&token::Type::RightParen, "Expected ')' after clauses.")?;
-        let mut body = self.statement()?;
-        if let Some(incr) = increment {
-            body = Statement::Block {
-                statements: vec![body, Statement::Expression{expression: incr}],
-            };
-        }
-        body = Statement::While{condition, body: Box::new(body)};
+        let mut body = Statement::While{keyword: keyword.clone(), condition, body: Box::new(self.statement()?), increment};
         if let Some(init) = initializer {
             body = Statement::Block{
+                brace: keyword,
                 statements: vec![init, body]
             };
         }
@@ -152,10 +242,12 @@ impl Parser {
     }
 
     fn if_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
         self.consume(&token::Type::LeftParen, "Expected '(' after 'if'.")?;
         let condition = self.expression()?;
         self.consume(&token::Type::RightParen, "Expected ')' after if condition.")?;
         Some(Statement::If {
+            keyword,
             condition,
             then_branch: Box::new(self.statement()?),
             else_branch: if self.match_types(&[token::Type::Else]) { Some(Box::new(self.statement()?)) } else { None },
@@ -163,9 +255,10 @@ impl Parser {
     }
 
     fn print_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
         let value = self.expression()?;
         self.consume(&token::Type::Semicolon, "Expected ';' after value.")?;
-        Some(Statement::Print{expression: value})
+        Some(Statement::Print{keyword, expression: value})
     }
 
     fn return_statement(&mut self) -> Option<Statement> {
@@ -182,11 +275,45 @@ impl Parser {
         })
     }
 
+    fn throw_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
+        let value = self.expression()?;
+        self.consume(&token::Type::Semicolon, "Expected ';' after thrown value.")?;
+        Some(Statement::Throw{keyword, value})
+    }
+
+    fn try_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
+        self.consume(&token::Type::LeftBrace, "Expected '{' after 'try'.")?;
+        let body = self.block_statement()?;
+        let catch = if self.match_types(&[token::Type::Catch]) {
+            self.consume(&token::Type::LeftParen, "Expected '(' after 'catch'.")?;
+            let name = self.consume(&token::Type::Identifier, "Expected error variable name.")?.clone();
+            self.consume(&token::Type::RightParen, "Expected ')' after error variable name.")?;
+            self.consume(&token::Type::LeftBrace, "Expected '{' before catch body.")?;
+            Some((name, self.block_statement()?))
+        } else {
+            None
+        };
+        let finally = if self.match_types(&[token::Type::Finally]) {
+            self.consume(&token::Type::LeftBrace, "Expected '{' after 'finally'.")?;
+            Some(self.block_statement()?)
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            self.error(Diagnostic::on_token(self.peek(), "Expected 'catch' or 'finally' after try block."));
+            return None;
+        }
+        Some(Statement::Try{keyword, body, catch, finally})
+    }
+
     fn while_statement(&mut self) -> Option<Statement> {
+        let keyword = self.previous().clone();
         self.consume(&token::Type::LeftParen, "Expected '(' after 'while'.")?;
         let condition = self.expression()?;
         self.consume(&token::Type::RightParen, "Expected ')' after condition.")?;
-        Some(Statement::While{condition, body: Box::new(self.statement()?)})
+        Some(Statement::While{keyword, condition, body: Box::new(self.statement()?), increment: None})
     }
 
     fn block_statement(&mut self) -> Option<Vec<Statement>> {
@@ -200,10 +327,20 @@ impl Parser {
 
     fn expression_statement(&mut self) -> Option<Statement> {
         let expression = self.expression()?;
-        self.consume(&token::Type::Semicolon, "Expected ';' after expression.")?;
+        if !(self.bare_expressions && self.is_at_end()) {
+            self.consume(&token::Type::Semicolon, "Expected ';' after expression.")?;
+        }
         Some(Statement::Expression{expression})
     }
 
+    // Operators, from the loosest binding to the tightest:
+    //
+    //   =  or  and  == !=  < <= > >=  |  ^  &  << >>  + -  * / % ~/  unary ! - ~  **  calls
+    //
+    // Bitwise operators bind tighter than comparisons, so `x & 1 == 0` tests the lowest bit of x.
+    // `**` is right-associative, so `2 ** 3 ** 2` is `2 ** (3 ** 2)`. It binds tighter than a unary
+    // operator before it, so `-2 ** 2` is `-(2 ** 2)`, but its right operand can be unary, as in
+    // `2 ** -1`.
     fn expression(&mut self) -> Option<Expression> {
         self.assignment()
     }
@@ -214,12 +351,13 @@ impl Parser {
             let equals = self.previous().clone();
             let value = self.assignment()?;
             match expr {
-                Expression::Variable{name, depth} => Some(Expression::Assignment{name, value: Box::new(value), depth}),
+                Expression::Variable{name, slot} => Some(Expression::Assignment{name, value: Box::new(value), slot}),
                 Expression::Get{object, name} => Some(Expression::Set{object, name, value: Box::new(value)}),
+                Expression::GetIndex{object, bracket, index} => Some(Expression::SetIndex{object, bracket, index, value: Box::new(value)}),
                 _ => {
                     // Note that we report an error, but don't propgate it further; this is because
                     // we *don't* need to synchronize.
-                    ERROR_REPORTER.lock().unwrap().error_on_token(&equals, "Invalid assignment target.");
+                    self.error(Diagnostic::on_token(&equals, "Invalid assignment target."));
                     Some(expr)
                 },
             }
@@ -259,15 +397,55 @@ impl Parser {
     }
 
     fn comparison(&mut self) -> Option<Expression> {
-        let mut expr = self.term()?;
+        let mut expr = self.bitwise_or()?;
         while self.match_types(&[token::Type::Greater, token::Type::GreaterEqual, token::Type::Less, token::Type::LessEqual]) {
             let operator = self.previous().clone();
-            let right = self.term()?;
+            let right = self.bitwise_or()?;
             expr = Expression::Binary{left: Box::new(expr), operator: operator.clone(), right: Box::new(right)};
         }
         Some(expr)
     }
 
+    fn bitwise_or(&mut self) -> Option<Expression> {
+        let mut expr = self.bitwise_xor()?;
+        while self.match_types(&[token::Type::Pipe]) {
+            let operator = self.previous().clone();
+            let right = self.bitwise_xor()?;
+            expr = Expression::Binary{left: Box::new(expr), operator, right: Box::new(right)};
+        }
+        Some(expr)
+    }
+
+    fn bitwise_xor(&mut self) -> Option<Expression> {
+        let mut expr = self.bitwise_and()?;
+        while self.match_types(&[token::Type::Caret]) {
+            let operator = self.previous().clone();
+            let right = self.bitwise_and()?;
+            expr = Expression::Binary{left: Box::new(expr), operator, right: Box::new(right)};
+        }
+        Some(expr)
+    }
+
+    fn bitwise_and(&mut self) -> Option<Expression> {
+        let mut expr = self.shift()?;
+        while self.match_types(&[token::Type::Ampersand]) {
+            let operator = self.previous().clone();
+            let right = self.shift()?;
+            expr = Expression::Binary{left: Box::new(expr), operator, right: Box::new(right)};
+        }
+        Some(expr)
+    }
+
+    fn shift(&mut self) -> Option<Expression> {
+        let mut expr = self.term()?;
+        while self.match_types(&[token::Type::LessLess, token::Type::GreaterGreater]) {
+            let operator = self.previous().clone();
+            let right = self.term()?;
+            expr = Expression::Binary{left: Box::new(expr), operator, right: Box::new(right)};
+        }
+        Some(expr)
+    }
+
     fn term(&mut self) -> Option<Expression> {
         let mut expr = self.factor()?;
         while self.match_types(&[token::Type::Minus, token::Type::Plus]) {
@@ -280,7 +458,7 @@ impl Parser {
 
     fn factor(&mut self) -> Option<Expression> {
         let mut expr = self.unary()?;
-        while self.match_types(&[token::Type::Slash, token::Type::Star]) {
+        while self.match_types(&[token::Type::Slash, token::Type::Star, token::Type::Percent, token::Type::TildeSlash]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
             expr = Expression::Binary{left: Box::new(expr), operator: operator.clone(), right: Box::new(right)};
@@ -289,15 +467,26 @@ impl Parser {
     }
 
     fn unary(&mut self) -> Option<Expression> {
-        if self.match_types(&[token::Type::Bang, token::Type::Minus]) {
+        if self.match_types(&[token::Type::Bang, token::Type::Minus, token::Type::Tilde]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
             Some(Expression::Unary{operator: operator.clone(), right: Box::new(right)})
         } else {
-            self.call()
+            self.power()
         }
     }
 
+    fn power(&mut self) -> Option<Expression> {
+        let expr = self.call()?;
+        if self.match_types(&[token::Type::StarStar]) {
+            let operator = self.previous().clone();
+            // Recursing back up to unary makes this right-associative.
+            let right = self.unary()?;
+            return Some(Expression::Binary{left: Box::new(expr), operator, right: Box::new(right)});
+        }
+        Some(expr)
+    }
+
     fn call(&mut self) -> Option<Expression> {
         let mut expr = self.primary()?;
         loop {
@@ -305,6 +494,10 @@ impl Parser {
                 expr = self.finish_call(expr)?;
             } else if self.match_types(&[token::Type::Dot]) {
                 expr = Expression::Get{object: Box::new(expr), name: self.consume(&token::Type::Identifier, "Expected property name after '.'.")?.clone()}
+            } else if self.match_types(&[token::Type::LeftBracket]) {
+                let index = self.expression()?;
+                let bracket = self.consume(&token::Type::RightBracket, "Expected ']' after index.")?.clone();
+                expr = Expression::GetIndex{object: Box::new(expr), bracket, index: Box::new(index)};
             } else {
                 break;
             }
@@ -318,7 +511,7 @@ impl Parser {
             loop {
                 if arguments.len() >= MAXIMUM_PARAMETER_COUNT {
                     // No need to return None and unwind; the parser isn't confused.
-                    ERROR_REPORTER.lock().unwrap().error_on_token(self.peek(), "Can't have more than 255 arguments.");
+                    self.error(Diagnostic::on_token(self.peek(), "Can't have more than 255 arguments."));
                 }
                 arguments.push(self.expression()?);
                 if !self.match_types(&[token::Type::Comma]) { break; }
@@ -332,19 +525,93 @@ impl Parser {
     }
 
     fn primary(&mut self) -> Option<Expression> {
+        // The segment of a string after an interpolation, which starts with its closing brace, is
+        // only ever reached here if the interpolated expression is empty or cut short, as in
+        // "${1 + }". It would otherwise be taken for a string operand.
+        if self.at_interpolation_end() {
+            self.error(Diagnostic::on_token(self.peek(), "Expected expression inside '${...}'."));
+            return None;
+        }
         // TODO: This is a little wasteful on the allocations.
         if self.match_types(&[token::Type::False, token::Type::True, token::Type::Nil, token::Type::Number(0.0), token::Type::String(String::new())]) {
-            Some(Expression::Literal{value: self.previous().token_type().clone().to_value()})
+            let token = self.previous().clone();
+            Some(Expression::Literal{value: token.token_type().clone().into_value(), token})
+        } else if self.match_types(&[token::Type::Interpolation(String::new())]) {
+            let quote = self.previous().clone();
+            let mut parts = Vec::new();
+            loop {
+                let segment = self.previous().clone();
+                if let token::Type::Interpolation(text) = segment.token_type() {
+                    if !text.is_empty() {
+                        parts.push(Expression::Literal{value: Value::String(text.clone()), token: segment});
+                    }
+                }
+                parts.push(self.expression()?);
+                if !self.match_types(&[token::Type::Interpolation(String::new())]) { break; }
+            }
+            // The rest of the string, after the last interpolation.
+            let token = self.consume(&token::Type::String(String::new()), "Expected '}' after interpolated expression.")?.clone();
+            if !matches!(token.token_type(), token::Type::String(text) if text.is_empty()) {
+                parts.push(Expression::Literal{value: token.token_type().clone().into_value(), token});
+            }
+            Some(Expression::Template{quote, parts})
+        } else if self.match_types(&[token::Type::Super]) {
+            let keyword = self.previous().clone();
+            self.consume(&token::Type::Dot, "Expected '.' after 'super'.")?;
+            let method = self.consume(&token::Type::Identifier, "Expected superclass method name.")?.clone();
+            Some(Expression::Super{keyword, method, slot: None})
         } else if self.match_types(&[token::Type::This]) {
-            Some(Expression::This{keyword: self.previous().clone(), depth: None})
+            Some(Expression::This{keyword: self.previous().clone(), slot: None})
         } else if self.match_types(&[token::Type::Identifier]) {
-            Some(Expression::Variable{name: self.previous().clone(), depth: None})
+            Some(Expression::Variable{name: self.previous().clone(), slot: None})
+        } else if self.match_types(&[token::Type::Fun]) {
+            let keyword = self.previous().clone();
+            self.consume(&token::Type::LeftParen, "Expected '(' after 'fun'.")?;
+            let params = self.parameters()?;
+            self.consume(&token::Type::LeftBrace, "Expected '{' before function body.")?;
+            Some(Expression::Lambda{keyword, params, body: self.block_statement()?})
+        } else if self.check(&token::Type::LeftParen) && self.at_arrow_parameters() {
+            self.advance();
+            let params = self.parameters()?;
+            let keyword = self.consume(&token::Type::Arrow, "Expected '=>' after parameters.")?.clone();
+            // The body is either a block, as for 'fun', or a single expression, returned as if by a
+            // return statement. A map literal has to be wrapped in parentheses to be returned.
+            let body = if self.match_types(&[token::Type::LeftBrace]) {
+                self.block_statement()?
+            } else {
+                let value = self.expression()?;
+                vec![Statement::Return{keyword: keyword.clone(), value: Some(value)}]
+            };
+            Some(Expression::Lambda{keyword, params, body})
         } else if self.match_types(&[token::Type::LeftParen]) {
+            let paren = self.previous().clone();
             let expr = self.expression()?;
             self.consume(&token::Type::RightParen, "Expected ')' after expression.")?;
-            Some(Expression::Grouping{ expression: Box::new(expr) })
+            Some(Expression::Grouping{paren, expression: Box::new(expr)})
+        } else if self.match_types(&[token::Type::LeftBracket]) {
+            let bracket = self.previous().clone();
+            let mut elements = Vec::new();
+            while !self.check(&token::Type::RightBracket) {
+                elements.push(self.expression()?);
+                if !self.match_types(&[token::Type::Comma]) { break; }
+            }
+            self.consume(&token::Type::RightBracket, "Expected ']' after list elements.")?;
+            Some(Expression::List{bracket, elements})
+        } else if self.match_types(&[token::Type::LeftBrace]) {
+            // A '{' at the start of a statement is always a block, so map literals only appear
+            // here, in expression position.
+            let brace = self.previous().clone();
+            let mut entries = Vec::new();
+            while !self.check(&token::Type::RightBrace) {
+                let key = self.expression()?;
+                self.consume(&token::Type::Colon, "Expected ':' after map key.")?;
+                entries.push((key, self.expression()?));
+                if !self.match_types(&[token::Type::Comma]) { break; }
+            }
+            self.consume(&token::Type::RightBrace, "Expected '}' after map entries.")?;
+            Some(Expression::Map{brace, entries})
         } else {
-            ERROR_REPORTER.lock().unwrap().error_on_token(self.peek(), "Expected expression.");
+            self.error(Diagnostic::on_token(self.peek(), "Expected expression."));
             None
         }
     }
@@ -359,6 +626,10 @@ impl Parser {
         false
     }
 
+    fn at_interpolation_end(&self) -> bool {
+        matches!(self.peek().token_type(), token::Type::String(_) | token::Type::Interpolation(_)) && self.peek().lexeme().starts_with('}')
+    }
+
     fn check(&self, token_type: &token::Type) -> bool {
         if self.is_at_end() {
             false
@@ -374,6 +645,30 @@ impl Parser {
         self.previous()
     }
 
+    fn check_next(&self, token_type: &token::Type) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => mem::discriminant(token.token_type()) == mem::discriminant(token_type),
+            None => false,
+        }
+    }
+
+    // Whether the parenthesised list starting at the current token is followed by '=>', making it the
+    // parameters of an arrow function rather than a grouping. Only lists of bare names can be.
+    fn at_arrow_parameters(&self) -> bool {
+        let is = |offset: usize, token_type: &token::Type| match self.tokens.get(self.current + offset) {
+            Some(token) => mem::discriminant(token.token_type()) == mem::discriminant(token_type),
+            None => false,
+        };
+        let mut offset = 1;
+        if is(offset, &token::Type::Identifier) {
+            offset += 1;
+            while is(offset, &token::Type::Comma) && is(offset + 1, &token::Type::Identifier) {
+                offset += 2;
+            }
+        }
+        is(offset, &token::Type::RightParen) && is(offset + 1, &token::Type::Arrow)
+    }
+
     fn is_at_end(&self) -> bool {
         mem::discriminant(self.peek().token_type()) == mem::discriminant(&token::Type::EOF)
     }
@@ -390,11 +685,15 @@ impl Parser {
         if self.check(token_type) {
             Some(self.advance())
         } else {
-            ERROR_REPORTER.lock().unwrap().error_on_token(self.peek(), message);
+            self.error(Diagnostic::on_token(self.peek(), message));
             None
         }
     }
 
+    fn error(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
     fn synchronize(&mut self) {
         self.advance();
         while !self.is_at_end() {
@@ -410,7 +709,10 @@ impl Parser {
                 | token::Type::If
                 | token::Type::While
                 | token::Type::Print
-                | token::Type::Return => return,
+                | token::Type::Return
+                | token::Type::Throw
+                | token::Type::Try
+                | token::Type::Import => return,
                 _ => {}
             }
 
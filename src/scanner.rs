@@ -1,26 +1,33 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
 use crate::token;
-use crate::token::Token;
-use crate::error_reporter::ERROR_REPORTER;
+use crate::token::{Span, Token};
+use crate::diagnostic::{Diagnostic, Source};
 
 lazy_static! {
     static ref KEYWORDS: HashMap<&'static str, token::Type> = {
         let mut m = HashMap::new();
         m.insert("and", token::Type::And);
+        m.insert("break", token::Type::Break);
+        m.insert("catch", token::Type::Catch);
         m.insert("class", token::Type::Class);
+        m.insert("continue", token::Type::Continue);
         m.insert("else", token::Type::Else);
         m.insert("false", token::Type::False);
+        m.insert("finally", token::Type::Finally);
         m.insert("for", token::Type::For);
         m.insert("fun", token::Type::Fun);
         m.insert("if", token::Type::If);
+        m.insert("import", token::Type::Import);
         m.insert("nil", token::Type::Nil);
         m.insert("or", token::Type::Or);
         m.insert("print", token::Type::Print);
         m.insert("return", token::Type::Return);
         m.insert("super", token::Type::Super);
         m.insert("this", token::Type::This);
+        m.insert("throw", token::Type::Throw);
         m.insert("true", token::Type::True);
+        m.insert("try", token::Type::Try);
         m.insert("var", token::Type::Var);
         m.insert("while", token::Type::While);
         m
@@ -35,34 +42,59 @@ lazy_static! {
 */
 // TODO: use an iterator over chars rather than a Vec<char> here.
 pub struct Scanner {
+    file: Rc<Source>,
     source: Vec<char>,
+    // The byte offset of each character, plus one past the end.
+    offsets: Vec<usize>,
     tokens: Vec<Token>,
+    diagnostics: Vec<Diagnostic>,
     start: usize,
     current: usize,
     line: usize,
+    // Where the current line starts, so columns can be worked out.
+    line_start: usize,
+    // Where the current token starts, as tokens can span lines.
+    start_line: usize,
+    start_column: usize,
+    // For each string interpolation we're inside, how many braces are open in its expression. The
+    // brace that closes it goes back to scanning the rest of the string.
+    interpolations: Vec<usize>,
 }
 
 impl Scanner {
-    pub fn new(source: &str) -> Self {
+    pub fn new(file: Rc<Source>) -> Self {
+        let source = &file.text;
         Self {
             source: source.chars().collect(),
+            offsets: source.char_indices().map(|(offset, _)| offset).chain(std::iter::once(source.len())).collect(),
+            file,
             tokens: Vec::new(),
+            diagnostics: Vec::new(),
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            interpolations: Vec::new(),
         }
     }
 
     // TODO: self.tokens is not trivial to clone; we should avoid it here.
     // we consume the scanner here; maybe we can keep this in the future.
-    pub fn scan_tokens(mut self) -> Vec<Token> {
+    pub fn scan_tokens(mut self) -> (Vec<Token>, Vec<Diagnostic>) {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.current - self.line_start + 1;
             self.scan_token();
         }
-        
-        self.tokens.push(Token::new(token::Type::EOF, String::new(), self.line));
-        self.tokens
+
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.current - self.line_start + 1;
+        self.tokens.push(Token::with_span(token::Type::EOF, String::new(), self.span()));
+        (self.tokens, self.diagnostics)
     }
 
     fn is_at_end(&self) -> bool {
@@ -70,32 +102,80 @@ impl Scanner {
     }
 
     fn scan_token(&mut self) {
-        let c = self.advance();
+        let c = *self.advance();
         match c {
             '(' => self.add_token(token::Type::LeftParen),
             ')' => self.add_token(token::Type::RightParen),
-            '{' => self.add_token(token::Type::LeftBrace),
-            '}' => self.add_token(token::Type::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(token::Type::LeftBrace);
+            },
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string_segment(self.current);
+                },
+                Some(depth) => {
+                    *depth -= 1;
+                    self.add_token(token::Type::RightBrace);
+                },
+                None => self.add_token(token::Type::RightBrace),
+            },
+            '[' => self.add_token(token::Type::LeftBracket),
+            ']' => self.add_token(token::Type::RightBracket),
+            ':' => self.add_token(token::Type::Colon),
             ',' => self.add_token(token::Type::Comma),
             '.' => self.add_token(token::Type::Dot),
             '-' => self.add_token(token::Type::Minus),
             '+' => self.add_token(token::Type::Plus),
             ';' => self.add_token(token::Type::Semicolon),
-            '*' => self.add_token(token::Type::Star),
+            '%' => self.add_token(token::Type::Percent),
+            '&' => self.add_token(token::Type::Ampersand),
+            '|' => self.add_token(token::Type::Pipe),
+            '^' => self.add_token(token::Type::Caret),
+            '*' => {
+                let token = if self.match_next('*') { token::Type::StarStar } else { token::Type::Star };
+                self.add_token(token);
+            },
+            // `//` starts a comment, so integer division is `~/` instead.
+            '~' => {
+                let token = if self.match_next('/') { token::Type::TildeSlash } else { token::Type::Tilde };
+                self.add_token(token);
+            },
             '!' => {
                 let token = if self.match_next('=') { token::Type::BangEqual } else { token::Type::Bang };
                 self.add_token(token);
             },
             '=' => {
-                let token = if self.match_next('=') { token::Type::EqualEqual } else { token::Type::Equal };
+                let token = if self.match_next('=') {
+                    token::Type::EqualEqual
+                } else if self.match_next('>') {
+                    token::Type::Arrow
+                } else {
+                    token::Type::Equal
+                };
                 self.add_token(token);
             },
             '<' => {
-                let token = if self.match_next('=') { token::Type::LessEqual } else { token::Type::Less };
+                let token = if self.match_next('=') {
+                    token::Type::LessEqual
+                } else if self.match_next('<') {
+                    token::Type::LessLess
+                } else {
+                    token::Type::Less
+                };
                 self.add_token(token);
             },
             '>' => {
-                let token = if self.match_next('=') { token::Type::GreaterEqual } else { token::Type::Greater };
+                let token = if self.match_next('=') {
+                    token::Type::GreaterEqual
+                } else if self.match_next('>') {
+                    token::Type::GreaterGreater
+                } else {
+                    token::Type::Greater
+                };
                 self.add_token(token);
             },
             '/' => {
@@ -108,15 +188,16 @@ impl Scanner {
                 }
             },
             ' ' | '\r' | '\t' => {},
-            '\n' => self.line += 1,
+            '\n' => self.new_line(),
             '"' => self.string(),
+            'r' if *self.peek() == '"' => self.raw_string(),
             _ => {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     self.number();
                 } else if c.is_alphabetic() {
                     self.identifier();
                 } else {
-                    ERROR_REPORTER.lock().unwrap().error(self.line, "Unexpected character.");
+                    self.diagnostics.push(Diagnostic::error(Some(self.span()), "Unexpected character."));
                 }
             },
         }
@@ -128,7 +209,26 @@ impl Scanner {
     }
 
     fn add_token(&mut self, token_type: token::Type) {
-        self.tokens.push(Token::new(token_type, self.source[self.start..self.current].iter().collect(), self.line));
+        let span = self.span();
+        self.tokens.push(Token::with_span(token_type, self.source[self.start..self.current].iter().collect(), span));
+    }
+
+    // The span of the token scanned so far.
+    fn span(&self) -> Span {
+        let offset = self.offsets[self.start];
+        Span {
+            source: Some(self.file.clone()),
+            line: self.start_line,
+            column: self.start_column,
+            offset,
+            length: self.offsets[self.current] - offset,
+        }
+    }
+
+    // Called after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
     }
 
     fn match_next(&mut self, expected: char) -> bool {
@@ -148,32 +248,208 @@ impl Scanner {
         self.source.get(self.current + 1).unwrap_or(&'\0')
     }
 
+    // Regular strings can run over several lines, and have their escape sequences replaced. Three
+    // quotes start a multi-line string instead.
     fn string(&mut self) {
-        while *self.peek() != '"' && !self.is_at_end() {
-            if *self.peek() == '\n' {
-                self.line += 1;
-            }
+        if *self.peek() == '"' && *self.peek_next() == '"' {
+            self.advance();
+            self.advance();
+            return self.multi_line_string();
+        }
+        self.string_segment(self.current);
+    }
+
+    // Scans a regular string from the given index up to its closing quote, or up to the next `${`.
+    // An interpolated string like "a ${b} c" is scanned into an Interpolation token for "a ", the
+    // tokens of the expression, and a String token for the rest, " c"; a string with several
+    // interpolations has an Interpolation token before each of them.
+    fn string_segment(&mut self, contents_start: usize) {
+        while *self.peek() != '"' && !(*self.peek() == '$' && *self.peek_next() == '{') && !self.is_at_end() {
+            self.string_char();
+        }
+        if self.is_at_end() {
+            self.diagnostics.push(Diagnostic::error(Some(self.span()), "Unterminated string."));
+            return;
+        }
+        let contents: Vec<usize> = (contents_start..self.current).collect();
+        let value = self.unescape(&contents);
+        if self.match_next('"') {
+            self.add_token(token::Type::String(value));
+        } else {
+            // Capture ${
+            self.advance();
             self.advance();
+            self.interpolations.push(0);
+            self.add_token(token::Type::Interpolation(value));
+        }
+    }
+
+    // Consumes a char of a string that isn't its end. An escaped quote, backslash or dollar sign is
+    // taken along with the backslash, so that it doesn't end the string or start an interpolation.
+    fn string_char(&mut self) {
+        let c = *self.advance();
+        match c {
+            '\\' if matches!(*self.peek(), '"' | '\\' | '$') => {
+                self.advance();
+            },
+            '\n' => self.new_line(),
+            _ => {},
+        }
+    }
+
+    fn at_triple_quote(&self) -> bool {
+        self.source.get(self.current..self.current + 3) == Some(&['"', '"', '"'])
+    }
+
+    // """...""" strings. A line break straight after the opening quotes, and whitespace on the line
+    // of the closing ones, are left out, and then the indentation all the remaining lines share is
+    // taken off each of them, so the string can be indented along with the code around it.
+    fn multi_line_string(&mut self) {
+        let contents_start = self.current;
+        while !self.at_triple_quote() && !self.is_at_end() {
+            self.string_char();
         }
-        
         if self.is_at_end() {
-            ERROR_REPORTER.lock().unwrap().error(self.line, "Unterminated string.");
+            self.diagnostics.push(Diagnostic::error(Some(self.span()), "Unterminated string."));
+            return;
+        }
+        let contents_end = self.current;
+        for _ in 0..3 {
+            self.advance();
+        }
+
+        let mut lines = Vec::new();
+        let mut line_start = contents_start;
+        for i in contents_start..contents_end {
+            if self.source[i] == '\n' {
+                lines.push(line_start..i);
+                line_start = i + 1;
+            }
+        }
+        lines.push(line_start..contents_end);
+        let is_blank = |line: &std::ops::Range<usize>| self.source[line.clone()].iter().all(|c| *c == ' ' || *c == '\t');
+        if lines.len() > 1 && is_blank(&lines[0]) {
+            lines.remove(0);
+        }
+        if lines.len() > 1 && is_blank(&lines[lines.len() - 1]) {
+            lines.pop();
+        }
+        let indent = lines.iter().filter(|line| !is_blank(line)).map(|line| {
+            self.source[line.clone()].iter().take_while(|c| **c == ' ' || **c == '\t').count()
+        }).min().unwrap_or(0);
+
+        let mut contents = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                // The line break before this line.
+                contents.push(line.start - 1);
+            }
+            contents.extend((line.start + indent).min(line.end)..line.end);
         }
+        let value = self.unescape(&contents);
+        self.add_token(token::Type::String(value));
+    }
 
+    // r"..." strings are taken exactly as written.
+    fn raw_string(&mut self) {
+        // Capture opening "
+        self.advance();
+        while *self.peek() != '"' && !self.is_at_end() {
+            if *self.advance() == '\n' {
+                self.new_line();
+            }
+        }
+        if self.is_at_end() {
+            self.diagnostics.push(Diagnostic::error(Some(self.span()), "Unterminated string."));
+            return;
+        }
         // Capture closing "
         self.advance();
-        // Trim enclosing "
-        self.add_token(token::Type::String(self.source[(self.start + 1)..(self.current - 1)].iter().collect()));
+        // Trim r" and "
+        self.add_token(token::Type::String(self.source[(self.start + 2)..(self.current - 1)].iter().collect()));
+    }
+
+    // Builds the value of a string from the chars at the given indexes, replacing escape sequences.
+    // Invalid ones are reported and left out.
+    fn unescape(&mut self, indexes: &[usize]) -> String {
+        let mut value = String::new();
+        let mut i = 0;
+        while i < indexes.len() {
+            let c = self.source[indexes[i]];
+            i += 1;
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let start = indexes[i - 1];
+            let escape = indexes.get(i).map(|index| self.source[*index]);
+            i += 1;
+            let unescaped = match escape {
+                Some('n') => Some('\n'),
+                Some('t') => Some('\t'),
+                Some('r') => Some('\r'),
+                Some('0') => Some('\0'),
+                Some('\\') => Some('\\'),
+                Some('"') => Some('"'),
+                Some('$') => Some('$'),
+                Some('u') => {
+                    // \u{...}, with one to six hex digits.
+                    let mut end = i;
+                    if indexes.get(end).map(|index| self.source[*index]) == Some('{') {
+                        end += 1;
+                        while end < indexes.len() && self.source[indexes[end]] != '}' && end - i <= 7 {
+                            end += 1;
+                        }
+                    }
+                    let digits: String = indexes.get(i + 1..end).unwrap_or(&[]).iter().map(|index| self.source[*index]).collect();
+                    let closed = indexes.get(end).map(|index| self.source[*index]) == Some('}');
+                    i = if closed { end + 1 } else { end };
+                    let code_point = if closed && !digits.is_empty() && digits.len() <= 6 { u32::from_str_radix(&digits, 16).ok() } else { None };
+                    match code_point.and_then(char::from_u32) {
+                        Some(c) => Some(c),
+                        None => {
+                            let last = indexes[(i - 1).min(indexes.len() - 1)];
+                            self.escape_error(start, last + 1, "Invalid unicode escape sequence.");
+                            None
+                        },
+                    }
+                },
+                Some(_) => {
+                    self.escape_error(start, indexes[i - 1] + 1, "Unknown escape sequence.");
+                    None
+                },
+                None => {
+                    self.escape_error(start, start + 1, "Unknown escape sequence.");
+                    None
+                },
+            };
+            value.extend(unescaped);
+        }
+        value
+    }
+
+    // Reports a bad escape sequence, between the given char indexes, as an error of its own.
+    fn escape_error(&mut self, start: usize, end: usize, message: &str) {
+        let line_start = self.source[..start].iter().rposition(|c| *c == '\n').map_or(0, |newline| newline + 1);
+        let span = Span {
+            source: Some(self.file.clone()),
+            line: self.start_line + self.source[self.start..start].iter().filter(|c| **c == '\n').count(),
+            column: start - line_start + 1,
+            offset: self.offsets[start],
+            length: self.offsets[end] - self.offsets[start],
+        };
+        let lexeme: String = self.source[start..end].iter().collect();
+        self.diagnostics.push(Diagnostic::at(span, &lexeme, message));
     }
 
     fn number(&mut self) {
-        while self.peek().is_digit(10) {
+        while self.peek().is_ascii_digit() {
             self.advance();
         }
-        if *self.peek() == '.' && self.peek_next().is_digit(10) {
+        if *self.peek() == '.' && self.peek_next().is_ascii_digit() {
             // consume the .
             self.advance();
-            while self.peek().is_digit(10) {
+            while self.peek().is_ascii_digit() {
                 self.advance();
             }
         }
@@ -181,7 +457,7 @@ impl Scanner {
     }
 
     fn identifier(&mut self) {
-        while self.peek().is_alphabetic() || self.peek().is_digit(10) {
+        while self.peek().is_alphabetic() || self.peek().is_ascii_digit() {
             self.advance();
         }
         self.add_token(KEYWORDS.get(&*self.source[self.start..self.current].iter().collect::<String>()).unwrap_or(&token::Type::Identifier).clone());
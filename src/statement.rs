@@ -1,15 +1,16 @@
 
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
 
 use crate::expression;
-use crate::token::Token;
+use crate::token::{Span, Token};
 use crate::expression::Value;
-use crate::environment::Environment;
-use crate::callable::LoxCallable;
+use crate::environment::{Environment, Scope};
+use crate::callable::{LoxCallable, Method};
 use crate::util::UnwindType;
 use crate::lox_class::LoxClass;
 use crate::expression::ClassType;
-use crate::error_reporter::ERROR_REPORTER;
+use crate::gc;
+use crate::diagnostic::Diagnostic;
 
 #[derive(PartialEq)]
 pub enum FunctionType {
@@ -19,6 +20,12 @@ pub enum FunctionType {
     Initializer,
 }
 
+#[derive(PartialEq)]
+pub enum LoopType {
+    None,
+    Loop,
+}
+
 #[derive(Clone)]
 pub enum Statement {
     Expression {
@@ -30,6 +37,7 @@ pub enum Statement {
         body: Vec<Statement>,
     },
     Print {
+        keyword: Token,
         expression: expression::Expression,
     },
     Return {
@@ -41,39 +49,147 @@ pub enum Statement {
         initializer: Option<expression::Expression>,
     },
     Block {
-        statements: Vec<Statement>,    
+        brace: Token,
+        statements: Vec<Statement>,
     },
     If {
+        keyword: Token,
         condition: expression::Expression,
         then_branch: Box<Statement>,
         else_branch: Option<Box<Statement>>,
     },
     While {
+        keyword: Token,
         condition: expression::Expression,
         body: Box<Statement>,
+        increment: Option<expression::Expression>,
+    },
+    Break {
+        keyword: Token,
+    },
+    Continue {
+        keyword: Token,
     },
     Class {
         name: Token,
+        superclass: Option<expression::Expression>,
         methods: Vec<Statement>,
     },
+    Throw {
+        keyword: Token,
+        value: expression::Expression,
+    },
+    // Each part is a block of its own; the catch's block also holds the error variable.
+    Try {
+        keyword: Token,
+        body: Vec<Statement>,
+        catch: Option<(Token, Vec<Statement>)>,
+        finally: Option<Vec<Statement>>,
+    },
+    // Defines names from another file as globals. The module is loaded before anything runs, which
+    // fills in the bindings; `import "path";` with no names takes everything it declares.
+    Import {
+        keyword: Token,
+        path: Token,
+        names: Vec<Token>,
+        bindings: Vec<(String, Value)>,
+    },
+}
+
+// The same s-expressions as Expression, for dumping the syntax tree.
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::Expression{expression} => write!(f, "(; {})", expression),
+            Statement::Function{name, params, body} => write!(f, "(fun {} {})", name.lexeme(), fmt_function(params, body)),
+            Statement::Print{keyword: _, expression} => write!(f, "(print {})", expression),
+            Statement::Return{keyword: _, value: Some(value)} => write!(f, "(return {})", value),
+            Statement::Return{keyword: _, value: None} => write!(f, "(return)"),
+            Statement::Var{name, initializer: Some(initializer)} => write!(f, "(var {} {})", name.lexeme(), initializer),
+            Statement::Var{name, initializer: None} => write!(f, "(var {})", name.lexeme()),
+            Statement::Block{brace: _, statements} => write!(f, "(block{})", fmt_statements(statements)),
+            Statement::If{keyword: _, condition, then_branch, else_branch: Some(else_branch)} => write!(f, "(if {} {} {})", condition, then_branch, else_branch),
+            Statement::If{keyword: _, condition, then_branch, else_branch: None} => write!(f, "(if {} {})", condition, then_branch),
+            Statement::While{keyword: _, condition, body, increment: Some(increment)} => write!(f, "(while {} {} {})", condition, body, increment),
+            Statement::While{keyword: _, condition, body, increment: None} => write!(f, "(while {} {})", condition, body),
+            Statement::Break{keyword: _} => write!(f, "(break)"),
+            Statement::Continue{keyword: _} => write!(f, "(continue)"),
+            Statement::Class{name, superclass, methods} => {
+                write!(f, "(class {}", name.lexeme())?;
+                if let Some(superclass) = superclass {
+                    write!(f, " < {}", superclass)?;
+                }
+                write!(f, "{})", fmt_statements(methods))
+            },
+            Statement::Throw{keyword: _, value} => write!(f, "(throw {})", value),
+            Statement::Try{keyword: _, body, catch, finally} => {
+                write!(f, "(try (block{})", fmt_statements(body))?;
+                if let Some((name, handler)) = catch {
+                    write!(f, " (catch {}{})", name.lexeme(), fmt_statements(handler))?;
+                }
+                if let Some(finally) = finally {
+                    write!(f, " (finally{})", fmt_statements(finally))?;
+                }
+                write!(f, ")")
+            },
+            Statement::Import{keyword: _, path, names, bindings: _} => {
+                let names: Vec<&str> = names.iter().map(Token::lexeme).collect();
+                write!(f, "(import {}{}{})", path.lexeme(), if names.is_empty() { "" } else { " " }, names.join(" "))
+            },
+        }
+    }
+}
+
+// Each statement preceded by a space.
+fn fmt_statements(statements: &[Statement]) -> String {
+    statements.iter().map(|statement| format!(" {}", statement)).collect()
+}
+
+// The parameters and body shared by function declarations and lambdas, e.g. `(a b) (return a)`.
+pub(crate) fn fmt_function(params: &[Token], body: &[Statement]) -> String {
+    let params: Vec<&str> = params.iter().map(Token::lexeme).collect();
+    format!("({}){}", params.join(" "), fmt_statements(body))
 }
 
 impl Statement {
+    // The part of a statement worth pointing at: the keyword or name introducing it, and the
+    // expression it's about. Bodies are left out, as they can run over many lines.
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Expression{expression} => expression.span(),
+            Statement::Function{name, params: _, body: _} => name.span(),
+            Statement::Print{keyword, expression} => keyword.span().to(expression.span()),
+            Statement::Return{keyword, value} => match value {
+                Some(value) => keyword.span().to(value.span()),
+                None => keyword.span(),
+            },
+            Statement::Var{name, initializer} => match initializer {
+                Some(init) => name.span().to(init.span()),
+                None => name.span(),
+            },
+            Statement::Block{brace, statements: _} => brace.span(),
+            Statement::If{keyword, condition, then_branch: _, else_branch: _} => keyword.span().to(condition.span()),
+            Statement::While{keyword, condition, body: _, increment: _} => keyword.span().to(condition.span()),
+            Statement::Break{keyword} | Statement::Continue{keyword} => keyword.span(),
+            Statement::Class{name, superclass: _, methods: _} => name.span(),
+            Statement::Throw{keyword, value} => keyword.span().to(value.span()),
+            Statement::Try{keyword, body: _, catch: _, finally: _} => keyword.span(),
+            Statement::Import{keyword, path, names: _, bindings: _} => keyword.span().to(path.span()),
+        }
+    }
+
     pub fn interpret(&self, environment: Rc<RefCell<Environment>>) -> Result<(), UnwindType> {
         match self {
             Statement::Expression{expression} => {expression.interpret(environment)?;},
-            Statement::Print{expression} => println!("{}", expression.interpret(environment)?),
+            Statement::Print{keyword: _, expression} => println!("{}", expression.interpret(environment)?),
             Statement::Var{name, initializer} => {
                 let value = if let Some(init) = initializer { init.interpret(environment.clone())? } else { Value::Nil };
-                environment.borrow_mut().define(name.lexeme().to_string(), value);
+                environment.borrow_mut().define(name.lexeme(), value);
             },
-            Statement::Block{statements} => {
-                let scoped_environment = Rc::new(RefCell::new(Environment::with_enclosing_scope(environment.clone())));
-                for statement in statements {
-                    statement.interpret(scoped_environment.clone())?;
-                }
+            Statement::Block{brace: _, statements} => {
+                interpret_block(statements, gc::new_environment(Environment::with_enclosing_scope(environment)))?;
             },
-            Statement::If{condition, then_branch, else_branch} => {
+            Statement::If{keyword: _, condition, then_branch, else_branch} => {
                 if condition.interpret(environment.clone())?.is_truthy() {
                     then_branch.interpret(environment)?;
                 } else {
@@ -82,13 +198,22 @@ impl Statement {
                     }
                 }
             },
-            Statement::While{condition, body} => {
+            Statement::While{keyword: _, condition, body, increment} => {
                 while condition.interpret(environment.clone())?.is_truthy() {
-                    body.interpret(environment.clone())?;
+                    match body.interpret(environment.clone()) {
+                        Err(UnwindType::Break) => break,
+                        Err(UnwindType::Continue) | Ok(()) => {},
+                        Err(unwind) => return Err(unwind),
+                    }
+                    if let Some(incr) = increment {
+                        incr.interpret(environment.clone())?;
+                    }
                 }
             },
+            Statement::Break{keyword: _} => return Err(UnwindType::Break),
+            Statement::Continue{keyword: _} => return Err(UnwindType::Continue),
             Statement::Function{name, params, body} => {
-                environment.borrow_mut().define(name.lexeme().to_string(), Value::Callable(Rc::new(LoxCallable::new(name.clone(), params.clone(), body.clone(), environment.clone(), false))));
+                environment.borrow_mut().define(name.lexeme(), Value::Callable(gc::new_callable(LoxCallable::new(name.clone(), params.clone(), body.clone(), environment.clone(), false))));
             },
             Statement::Return{keyword: _, value} => {
                 if let Some(expr) = value {
@@ -97,100 +222,166 @@ impl Statement {
                     return Err(UnwindType::Return(Value::Nil))
                 }
             },
-            Statement::Class{name, methods} => {
-                environment.borrow_mut().define(name.lexeme().to_string(), Value::Nil);
-                let mut final_methods = HashMap::new();
+            Statement::Class{name, superclass, methods} => {
+                let superclass = if let Some(expr) = superclass {
+                    let superclass_name = match expr {
+                        expression::Expression::Variable{name, slot: _} => name,
+                        _ => panic!("An invalid superclass snuck in!"),
+                    };
+                    match expr.interpret(environment.clone())? {
+                        Value::Callable(callable) => {
+                            if let Some(class) = callable.as_class() {
+                                Some(class)
+                            } else {
+                                return Err(UnwindType::error(superclass_name, "Superclass must be a class."));
+                            }
+                        },
+                        _ => {
+                            return Err(UnwindType::error(superclass_name, "Superclass must be a class."));
+                        },
+                    }
+                } else {
+                    None
+                };
+                let method_environment = if let Some(class) = &superclass {
+                    let mut super_environment = Environment::with_enclosing_scope(environment.clone());
+                    super_environment.define("super", Value::Callable(class.clone()));
+                    gc::new_environment(super_environment)
+                } else {
+                    environment.clone()
+                };
+                let mut final_methods: HashMap<String, Rc<dyn Method>> = HashMap::new();
                 for method in methods {
                     match method {
-                        Statement::Function{name: method_name, params, body} => { final_methods.insert(method_name.lexeme().to_string(), Rc::new(LoxCallable::new(method_name.clone(), params.clone(), body.clone(), environment.clone(), method_name.lexeme() == "init"))); },
+                        Statement::Function{name: method_name, params, body} => { final_methods.insert(method_name.lexeme().to_string(), gc::new_callable(LoxCallable::new(method_name.clone(), params.clone(), body.clone(), method_environment.clone(), method_name.lexeme() == "init"))); },
                         _ => panic!("An invalid method snuck in!"),
                     }
                 }
-                environment.borrow_mut().define(name.lexeme().to_string(), Value::Callable(Rc::new(LoxClass::new(name.lexeme().to_string(), final_methods))));
-            }
+                // Methods only look the class up once they're called, so it's fine to define it last.
+                environment.borrow_mut().define(name.lexeme(), Value::Callable(gc::new_callable(LoxClass::new(name.lexeme().to_string(), superclass, final_methods))));
+            },
+            Statement::Throw{keyword, value} => {
+                let value = value.interpret(environment)?;
+                return Err(UnwindType::Error(Diagnostic::thrown(keyword.span(), value)));
+            },
+            Statement::Try{keyword: _, body, catch, finally} => {
+                let result = match (interpret_block(body, gc::new_environment(Environment::with_enclosing_scope(environment.clone()))), catch) {
+                    (Err(UnwindType::Error(diagnostic)), Some((name, handler))) => {
+                        let mut catch_environment = Environment::with_enclosing_scope(environment.clone());
+                        catch_environment.define(name.lexeme(), diagnostic.into_value());
+                        interpret_block(handler, gc::new_environment(catch_environment))
+                    },
+                    (result, _) => result,
+                };
+                // The finally block runs however the rest of the statement finished, and anything
+                // leaving it early takes the place of that.
+                if let Some(finally) = finally {
+                    interpret_block(finally, gc::new_environment(Environment::with_enclosing_scope(environment)))?;
+                }
+                result?;
+            },
+            Statement::Import{keyword: _, path: _, names: _, bindings} => {
+                for (name, value) in bindings {
+                    environment.borrow_mut().define(name, value.clone());
+                }
+            },
         }
         Ok(())
     }
 
-    pub fn resolve(&mut self, scopes: &mut Vec<HashMap<String, bool>>, function_type: &FunctionType, class_type: &ClassType) {
+    pub fn resolve(&mut self, scopes: &mut Vec<Scope>, function_type: &FunctionType, class_type: &ClassType, loop_type: &LoopType, diagnostics: &mut Vec<Diagnostic>) {
         match self {
-            Statement::Block{statements} => {
-                scopes.push(HashMap::new());
-                for statement in statements {
-                    statement.resolve(scopes, function_type, class_type);
-                }
-                scopes.pop();
-            },
+            Statement::Block{brace: _, statements} => resolve_block(statements, Scope::new(), scopes, function_type, class_type, loop_type, diagnostics),
             Statement::Var{name, initializer} => {
                 if let Some(last) = scopes.last_mut() {
-                    if last.contains_key(name.lexeme()) {
-                        ERROR_REPORTER.lock().unwrap().error_on_token(name, "A variable with this name already exists in this scope.");
-                    }
-                    last.insert(name.lexeme().to_string(), false);
+                    last.declare(name, diagnostics);
                 }
                 if let Some(init) = initializer {
-                    init.resolve(scopes, class_type);
+                    init.resolve(scopes, class_type, diagnostics);
                 }
                 if let Some(last) = scopes.last_mut() {
-                    last.insert(name.lexeme().to_string(), true);
+                    last.define(name.lexeme());
                 }
             },
             Statement::Function{name, params, body} => {
                 if let Some(last) = scopes.last_mut() {
-                    if last.contains_key(name.lexeme()) {
-                        ERROR_REPORTER.lock().unwrap().error_on_token(name, "A variable with this name already exists in this scope.");
-                    }
-                    last.insert(name.lexeme().to_string(), true);
-                }
-                let new_function_type = FunctionType::Function;
-                scopes.push(HashMap::new());
-                for param in params {
-                    if let Some(last) = scopes.last_mut() {
-                        if last.contains_key(param.lexeme()) {
-                            ERROR_REPORTER.lock().unwrap().error_on_token(param, "A variable with this name already exists in this scope.");
-                        }
-                        last.insert(param.lexeme().to_string(), true);
-                    }
-                }
-                for statement in body {
-                    statement.resolve(scopes, &new_function_type, class_type);
+                    last.declare(name, diagnostics);
+                    last.define(name.lexeme());
                 }
-                scopes.pop();
+                resolve_function(params, body, &FunctionType::Function, scopes, class_type, diagnostics);
             },
-            Statement::Expression{expression} => expression.resolve(scopes, class_type),
-            Statement::If{condition, then_branch, else_branch} => {
-                condition.resolve(scopes, class_type);
-                then_branch.resolve(scopes, function_type, class_type);
+            Statement::Expression{expression} => expression.resolve(scopes, class_type, diagnostics),
+            Statement::If{keyword: _, condition, then_branch, else_branch} => {
+                condition.resolve(scopes, class_type, diagnostics);
+                then_branch.resolve(scopes, function_type, class_type, loop_type, diagnostics);
                 if let Some(branch) = else_branch {
-                    branch.resolve(scopes, function_type, class_type);
+                    branch.resolve(scopes, function_type, class_type, loop_type, diagnostics);
                 }
             },
-            Statement::Print{expression} => expression.resolve(scopes, class_type),
+            Statement::Print{keyword: _, expression} => expression.resolve(scopes, class_type, diagnostics),
             Statement::Return{keyword, value} => {
                 if *function_type == FunctionType::None {
-                    ERROR_REPORTER.lock().unwrap().error_on_token(keyword, "Can't return from top-level code.");
+                    diagnostics.push(Diagnostic::on_token(keyword, "Can't return from top-level code."));
                 }
                 if *function_type == FunctionType::Initializer && value.is_some() {
-                    ERROR_REPORTER.lock().unwrap().error_on_token(keyword, "Can't return a value from an initializer.");
+                    diagnostics.push(Diagnostic::on_token(keyword, "Can't return a value from an initializer."));
                 }
                 if let Some(expr) = value {
-                    expr.resolve(scopes, class_type)
+                    expr.resolve(scopes, class_type, diagnostics)
                 }
             },
-            Statement::While{condition, body} => {
-                condition.resolve(scopes, class_type);
-                body.resolve(scopes, function_type, class_type);
+            Statement::While{keyword: _, condition, body, increment} => {
+                condition.resolve(scopes, class_type, diagnostics);
+                body.resolve(scopes, function_type, class_type, &LoopType::Loop, diagnostics);
+                if let Some(incr) = increment {
+                    incr.resolve(scopes, class_type, diagnostics);
+                }
             },
-            Statement::Class{name, methods} => {
+            Statement::Break{keyword} => {
+                if *loop_type == LoopType::None {
+                    diagnostics.push(Diagnostic::on_token(keyword, "Can't use 'break' outside of a loop."));
+                }
+            },
+            Statement::Continue{keyword} => {
+                if *loop_type == LoopType::None {
+                    diagnostics.push(Diagnostic::on_token(keyword, "Can't use 'continue' outside of a loop."));
+                }
+            },
+            Statement::Throw{keyword: _, value} => value.resolve(scopes, class_type, diagnostics),
+            Statement::Try{keyword: _, body, catch, finally} => {
+                resolve_block(body, Scope::new(), scopes, function_type, class_type, loop_type, diagnostics);
+                if let Some((name, handler)) = catch {
+                    let mut scope = Scope::new();
+                    scope.declare(name, diagnostics);
+                    scope.define(name.lexeme());
+                    resolve_block(handler, scope, scopes, function_type, class_type, loop_type, diagnostics);
+                }
+                if let Some(finally) = finally {
+                    resolve_block(finally, Scope::new(), scopes, function_type, class_type, loop_type, diagnostics);
+                }
+            },
+            Statement::Class{name, superclass, methods} => {
                 if let Some(last) = scopes.last_mut() {
-                    if last.contains_key(name.lexeme()) {
-                        ERROR_REPORTER.lock().unwrap().error_on_token(name, "A variable with this name already exists in this scope.");
-                    }
-                    last.insert(name.lexeme().to_string(), false);
+                    last.declare(name, diagnostics);
+                    last.define(name.lexeme());
                 }
-                let new_class_type = ClassType::Class;
-                scopes.push(HashMap::new());
-                scopes.last_mut().unwrap().insert("this".to_string(), true);
+                let new_class_type = if let Some(expr) = superclass {
+                    if let expression::Expression::Variable{name: superclass_name, slot: _} = expr {
+                        if superclass_name.lexeme() == name.lexeme() {
+                            diagnostics.push(Diagnostic::on_token(superclass_name, "A class can't inherit from itself."));
+                        }
+                    }
+                    expr.resolve(scopes, class_type, diagnostics);
+                    let mut scope = Scope::new();
+                    scope.define("super");
+                    scopes.push(scope);
+                    ClassType::Subclass
+                } else {
+                    ClassType::Class
+                };
+                let mut scope = Scope::new();
+                scope.define("this");
+                scopes.push(scope);
                 for method in methods {
                     match method {
                         Statement::Function{name: method_name, params, body} => {
@@ -199,24 +390,48 @@ impl Statement {
                             } else {
                                 FunctionType::Method
                             };
-                            for param in params {
-                                if let Some(last) = scopes.last_mut() {
-                                    if last.contains_key(param.lexeme()) {
-                                        ERROR_REPORTER.lock().unwrap().error_on_token(param, "A variable with this name already exists in this scope.");
-                                    }
-                                    last.insert(param.lexeme().to_string(), true);
-                                }
-                            }
-                            for statement in body {
-                                statement.resolve(scopes, &new_function_type, &new_class_type);
-                            }
-                            scopes.pop();
+                            resolve_function(params, body, &new_function_type, scopes, &new_class_type, diagnostics);
                         }
                         _ => panic!("An invalid method snuck in!"),
                     }
                 }
                 scopes.pop();
+                if superclass.is_some() {
+                    scopes.pop();
+                }
             },
+            // Imports are only allowed at the top level, so everything they define is global.
+            Statement::Import{keyword: _, path: _, names: _, bindings: _} => {},
         }
     }
 }
+
+fn interpret_block(statements: &[Statement], environment: Rc<RefCell<Environment>>) -> Result<(), UnwindType> {
+    for statement in statements {
+        statement.interpret(environment.clone())?;
+    }
+    Ok(())
+}
+
+// Resolves the body of a function in a new scope holding its parameters.
+pub(crate) fn resolve_function(params: &[Token], body: &mut [Statement], function_type: &FunctionType, scopes: &mut Vec<Scope>, class_type: &ClassType, diagnostics: &mut Vec<Diagnostic>) {
+    let mut scope = Scope::new();
+    for param in params {
+        scope.declare(param, diagnostics);
+        scope.define(param.lexeme());
+    }
+    scopes.push(scope);
+    for statement in body {
+        statement.resolve(scopes, function_type, class_type, &LoopType::None, diagnostics);
+    }
+    scopes.pop();
+}
+
+// Resolves statements in a new scope, which may already have variables in it.
+fn resolve_block(statements: &mut [Statement], scope: Scope, scopes: &mut Vec<Scope>, function_type: &FunctionType, class_type: &ClassType, loop_type: &LoopType, diagnostics: &mut Vec<Diagnostic>) {
+    scopes.push(scope);
+    for statement in statements {
+        statement.resolve(scopes, function_type, class_type, loop_type, diagnostics);
+    }
+    scopes.pop();
+}
@@ -1,31 +1,38 @@
-use std::fmt;
+use std::{fmt, rc::Rc};
 
 use crate::expression::Value;
+use crate::diagnostic::Source;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub enum Type {
     // Single character tokens
-    LeftParen, RightParen, LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
+    LeftParen, RightParen, LeftBrace, RightBrace, LeftBracket, RightBracket,
+    Colon, Comma, Dot, Minus, Plus, Semicolon, Slash, Percent,
+    Ampersand, Pipe, Caret,
 
     // One or two character tokens
     Bang, BangEqual,
-    Equal, EqualEqual,
-    Greater, GreaterEqual,
-    Less, LessEqual,
+    Equal, EqualEqual, Arrow,
+    Greater, GreaterEqual, GreaterGreater,
+    Less, LessEqual, LessLess,
+    Star, StarStar,
+    Tilde, TildeSlash,
 
     // Literals
     Identifier, String(String), Number(f64),
+    // The part of a string before a `${`.
+    Interpolation(String),
 
     // Keywords
-    And, Class, Else, False, Fun, For, If, Nil, Or,
-    Print, Return, Super, This, True, Var, While,
+    And, Break, Catch, Class, Continue, Else, False, Finally, Fun, For, If, Nil, Or,
+    Import, Print, Return, Super, This, Throw, True, Try, Var, While,
 
     EOF
 }
 
 impl Type {
-    pub fn to_value(self) -> Value {
+    pub fn into_value(self) -> Value {
         match self {
             Type::String(s) => Value::String(s),
             Type::Number(n) => Value::Number(n),
@@ -37,19 +44,57 @@ impl Type {
     }
 }
 
+// A range of source text. Lines and columns count from 1, and columns are in characters; the offset
+// and length are in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    // The source this is in, which is kept for as long as anything refers to it. None for tokens
+    // that don't come from the source.
+    pub source: Option<Rc<Source>>,
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Span {
+    // Somewhere on a line, for tokens that don't come from the source.
+    pub fn on_line(line: usize) -> Self {
+        Self {
+            source: None,
+            line,
+            column: 0,
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    // The span from the start of this one to the end of another.
+    pub fn to(self, other: Span) -> Self {
+        Self {
+            length: (other.offset + other.length).saturating_sub(self.offset).max(self.length),
+            ..self
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Token {
     token_type: Type,
     lexeme: String,
-    line: usize,
+    span: Span,
 }
 
 impl Token {
     pub fn new(token_type: Type, lexeme: String, line: usize) -> Self {
+        Self::with_span(token_type, lexeme, Span::on_line(line))
+    }
+
+    pub fn with_span(token_type: Type, lexeme: String, span: Span) -> Self {
         Self {
             token_type,
             lexeme,
-            line,
+            span,
         }
     }
 
@@ -62,7 +107,11 @@ impl Token {
     }
 
     pub fn line(&self) -> usize {
-        self.line
+        self.span.line
+    }
+
+    pub fn span(&self) -> Span {
+        self.span.clone()
     }
 }
 
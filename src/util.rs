@@ -1,11 +1,92 @@
+use std::{cell::RefCell, collections::HashSet, fmt};
+
+use crate::token::Token;
 use crate::expression::Value;
+use crate::diagnostic::Diagnostic;
 
-pub const EX_USAGE: i32 = 64;
-pub const EX_DATAERR: i32 = 65;
-pub const EX_SOFTWARE: i32 = 70;
 pub const MAXIMUM_PARAMETER_COUNT: usize = 255;
+// How many calls deep a program can go, counting the top level as one, before it's stopped with a
+// stack overflow. Both backends stop at the same depth.
+pub const MAXIMUM_FRAME_COUNT: usize = 1024;
+
+// The integer operators, shared by both backends so that they fail the same way. Operands are
+// numbers like any other, but have to be whole and fit in 64 bits; the result is a number again.
+pub enum IntegerOperator {
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+pub fn integer_operation(operator: IntegerOperator, left: f64, right: f64) -> Result<f64, &'static str> {
+    let (left, right) = match (as_integer(left), as_integer(right)) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return Err("Operands must be integers."),
+    };
+    let result = match operator {
+        IntegerOperator::And => left & right,
+        IntegerOperator::Or => left | right,
+        IntegerOperator::Xor => left ^ right,
+        IntegerOperator::ShiftLeft | IntegerOperator::ShiftRight if !(0..64).contains(&right) => return Err("Shift amount must be between 0 and 63."),
+        IntegerOperator::ShiftLeft => left << right,
+        IntegerOperator::ShiftRight => left >> right,
+    };
+    Ok(result as f64)
+}
+
+pub fn bitwise_not(operand: f64) -> Result<f64, &'static str> {
+    as_integer(operand).map(|operand| !operand as f64).ok_or("Operand must be an integer.")
+}
+
+// Division rounded towards zero, so that `a == (a ~/ b) * b + a % b`.
+pub fn integer_divide(left: f64, right: f64) -> Result<f64, &'static str> {
+    if right == 0.0 {
+        Err("Division by zero.")
+    } else {
+        Ok((left / right).trunc())
+    }
+}
+
+fn as_integer(number: f64) -> Option<i64> {
+    if number.fract() == 0.0 && number >= i64::MIN as f64 && number < i64::MAX as f64 {
+        Some(number as i64)
+    } else {
+        None
+    }
+}
+
+thread_local! {
+    // The lists and maps being printed further up the stack, by address.
+    static PRINTING: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+// Prints a list or map with `print`, unless it's already being printed because it contains
+// itself, in which case `elided` is printed instead of going round forever.
+pub fn fmt_once<T>(
+    container: *const T,
+    elided: &str,
+    f: &mut fmt::Formatter<'_>,
+    print: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    let address = container as usize;
+    if !PRINTING.with(|printing| printing.borrow_mut().insert(address)) {
+        return write!(f, "{}", elided);
+    }
+    let result = print(f);
+    PRINTING.with(|printing| printing.borrow_mut().remove(&address));
+    result
+}
 
 pub enum UnwindType {
-    Error,
+    Error(Diagnostic),
     Return(Value),
+    Break,
+    Continue,
+}
+
+impl UnwindType {
+    pub fn error(token: &Token, message: &str) -> Self {
+        UnwindType::Error(Diagnostic::on_token(token, message))
+    }
 }
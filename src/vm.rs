@@ -0,0 +1,730 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::token;
+use crate::token::{Span, Token};
+use crate::chunk::{Chunk, Function, OpCode};
+use crate::expression::Value;
+use crate::callable::{Arity, Callable, Kind, Method};
+use crate::natives;
+use crate::util;
+use crate::util::{IntegerOperator, MAXIMUM_FRAME_COUNT};
+use crate::lox_class::{LoxClass, LoxInstance};
+use crate::lox_map::{LoxMap, MapKey};
+use crate::gc;
+use crate::gc::{Trace, Tracer};
+use crate::diagnostic::{Diagnostic, StackFrame};
+
+
+pub type Globals = Rc<RefCell<HashMap<String, Value>>>;
+
+// An upvalue points at a local on the stack until the local goes out of scope, at which point the
+// value is moved into the upvalue itself.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Upvalue::Closed(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+// A compiled function along with the variables it closes over. Methods are closures with a
+// receiver, which is placed in slot zero when they are called.
+pub struct VmClosure {
+    function: Rc<Function>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    globals: Globals,
+    receiver: Option<Value>,
+}
+
+impl Callable for VmClosure {
+    // Only used when something outside of the VM calls a closure; the VM pushes a frame instead.
+    fn call(self: Rc<Self>, arguments: Vec<Value>) -> Result<Value, Diagnostic> {
+        let mut vm = Vm::with_globals(self.globals.clone());
+        let argument_count = arguments.len();
+        vm.stack.push(Value::Callable(self.clone()));
+        vm.stack.extend(arguments);
+        vm.call_closure(self, argument_count)?;
+        vm.run(0)
+    }
+
+    fn arity(&self) -> Arity {
+        Arity::Exactly(self.function.arity)
+    }
+
+    fn as_vm_closure(self: Rc<Self>) -> Option<Rc<VmClosure>> {
+        Some(self)
+    }
+
+    fn name(&self) -> &str {
+        &self.function.name
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Function
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        for upvalue in &self.upvalues {
+            tracer.pointer(upvalue);
+        }
+        tracer.pointer(&self.globals);
+        if let Some(receiver) = &self.receiver {
+            receiver.trace(tracer);
+        }
+    }
+}
+
+impl Method for VmClosure {
+    fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> Rc<dyn Callable> {
+        gc::new_callable(VmClosure {
+            function: self.function.clone(),
+            upvalues: self.upvalues.clone(),
+            globals: self.globals.clone(),
+            receiver: Some(Value::Instance(instance)),
+        })
+    }
+}
+
+struct CallFrame {
+    closure: Rc<VmClosure>,
+    ip: usize,
+    // The index of slot zero of this frame on the stack.
+    base: usize,
+}
+
+// An installed try handler: where to carry on from when an error is raised, and how far to unwind
+// the frames and stack before doing so.
+struct Handler {
+    frame_count: usize,
+    stack_len: usize,
+    ip: usize,
+    // Finally handlers hold on to the error, to raise it again once the finally block has run.
+    finally: bool,
+}
+
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    handlers: Vec<Handler>,
+    // Errors put aside while finally blocks run, along with the stack slot of the placeholder
+    // standing in for each one.
+    raised: Vec<(usize, Diagnostic)>,
+    globals: Globals,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let globals = gc::new_globals();
+        for (name, value) in natives::globals() {
+            globals.borrow_mut().insert(name.to_string(), value);
+        }
+        Self::with_globals(globals)
+    }
+
+    fn with_globals(globals: Globals) -> Self {
+        Self {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            raised: Vec::new(),
+            globals,
+        }
+    }
+
+    pub(crate) fn interpret(&mut self, function: Function) -> Result<Value, Diagnostic> {
+        let closure = gc::new_callable(VmClosure {
+            function: Rc::new(function),
+            upvalues: Vec::new(),
+            globals: self.globals.clone(),
+            receiver: None,
+        });
+        self.stack.push(Value::Callable(closure.clone()));
+        let result = self.call_closure(closure, 0).and_then(|_| self.run(0)).map_err(|diagnostic| self.with_stack_trace(diagnostic));
+        if result.is_err() {
+            // Closures that escaped before the error need to keep their values.
+            self.close_upvalues(0);
+            self.stack.clear();
+            self.frames.clear();
+            self.handlers.clear();
+            self.raised.clear();
+        }
+        result
+    }
+
+    // Must be called before the frames are unwound. Errors that have already been through a finally
+    // block keep the trace from where they were first raised.
+    fn with_stack_trace(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        if self.frames.len() > 1 && diagnostic.stack_trace.is_empty() {
+            diagnostic.stack_trace = self.frames.iter().enumerate().map(|(depth, frame)| {
+                let span = frame.closure.function.chunk.span(frame.ip.saturating_sub(1));
+                StackFrame {
+                    function: if depth == 0 { None } else { Some(frame.closure.function.name.clone()) },
+                    source: span.source,
+                    line: span.line,
+                }
+            }).collect();
+        }
+        diagnostic
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get(name).cloned()
+    }
+
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.globals.borrow().iter().map(|(name, value)| (name.clone(), value.clone())).collect()
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().insert(name.to_string(), value);
+    }
+
+    // Runs until the frame at the given depth returns, and produces its return value. Errors can be
+    // caught by handlers installed above that depth.
+    fn run(&mut self, stop_depth: usize) -> Result<Value, Diagnostic> {
+        loop {
+            match self.execute(stop_depth) {
+                Err(diagnostic) => self.catch(diagnostic, stop_depth)?,
+                result => return result,
+            }
+        }
+    }
+
+    // Unwinds to the innermost handler, if there's one to catch the error.
+    fn catch(&mut self, diagnostic: Diagnostic, stop_depth: usize) -> Result<(), Diagnostic> {
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frame_count > stop_depth => self.handlers.pop().unwrap(),
+            _ => return Err(diagnostic),
+        };
+        let diagnostic = if handler.finally { self.with_stack_trace(diagnostic) } else { diagnostic };
+        self.close_upvalues(handler.stack_len);
+        self.frames.truncate(handler.frame_count);
+        self.stack.truncate(handler.stack_len);
+        self.frame_mut().ip = handler.ip;
+        if handler.finally {
+            self.raised.retain(|(slot, _)| *slot < handler.stack_len);
+            self.raised.push((handler.stack_len, diagnostic));
+            self.stack.push(Value::Nil);
+        } else {
+            self.stack.push(diagnostic.into_value());
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, stop_depth: usize) -> Result<Value, Diagnostic> {
+        loop {
+            match OpCode::from_byte(self.read_byte()) {
+                OpCode::Constant => {
+                    let index = self.read_short() as usize;
+                    let value = self.frame().closure.function.chunk.constants[index].clone();
+                    self.stack.push(value);
+                },
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::True),
+                OpCode::False => self.stack.push(Value::False),
+                OpCode::Pop => {
+                    self.pop();
+                },
+                OpCode::GetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot].clone());
+                },
+                OpCode::SetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0).clone();
+                },
+                OpCode::GetGlobal => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let value = closure.globals.borrow().get(name).cloned();
+                    match value {
+                        Some(value) => self.stack.push(value),
+                        None => return self.error(name, &format!("Undefined variable '{}'.", name)),
+                    }
+                },
+                OpCode::DefineGlobal => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let value = self.pop();
+                    closure.globals.borrow_mut().insert(name.to_string(), value);
+                },
+                OpCode::SetGlobal => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let mut globals = closure.globals.borrow_mut();
+                    if let Some(global) = globals.get_mut(name) {
+                        *global = self.peek(0).clone();
+                    } else {
+                        return self.error(name, &format!("Undefined variable '{}'.", name));
+                    }
+                },
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let value = match &*self.frame().closure.upvalues[index].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                },
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let value = self.peek(0).clone();
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                },
+                OpCode::GetProperty => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let object = self.pop();
+                    match object.get_property(&self.name_token(name)) {
+                        Ok(value) => self.stack.push(value),
+                        Err(message) => return self.error(name, &message),
+                    }
+                },
+                OpCode::SetProperty => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let value = self.pop();
+                    let object = self.pop();
+                    if let Err(message) = object.set_property(&self.name_token(name), value.clone()) {
+                        return self.error(name, &message);
+                    }
+                    self.stack.push(value);
+                },
+                OpCode::GetSuper => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let superclass = self.pop();
+                    let instance = self.pop();
+                    match (superclass, instance) {
+                        (Value::Callable(superclass), Value::Instance(instance)) => {
+                            let superclass = superclass.as_class().expect("'super' resolved to something that isn't a class!");
+                            match superclass.find_method(name) {
+                                Some(method) => self.stack.push(Value::Callable(method.bind(instance))),
+                                None => return self.error(name, &format!("Undefined property '{}'.", name)),
+                            }
+                        },
+                        _ => panic!("'super' or 'this' resolved to an unexpected value!"),
+                    }
+                },
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    match object.get_index(index) {
+                        Ok(value) => self.stack.push(value),
+                        Err(message) => return self.error("]", &message),
+                    }
+                },
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    if let Err(message) = object.set_index(index, value.clone()) {
+                        return self.error("]", &message);
+                    }
+                    self.stack.push(value);
+                },
+                OpCode::Equal => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(Value::from_bool(left == right));
+                },
+                OpCode::Greater => {
+                    let (left, right) = self.pop_numbers(">")?;
+                    self.stack.push(Value::from_bool(left > right));
+                },
+                OpCode::GreaterEqual => {
+                    let (left, right) = self.pop_numbers(">=")?;
+                    self.stack.push(Value::from_bool(left >= right));
+                },
+                OpCode::Less => {
+                    let (left, right) = self.pop_numbers("<")?;
+                    self.stack.push(Value::from_bool(left < right));
+                },
+                OpCode::LessEqual => {
+                    let (left, right) = self.pop_numbers("<=")?;
+                    self.stack.push(Value::from_bool(left <= right));
+                },
+                OpCode::Add => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    match (left, right) {
+                        (Value::Number(l), Value::Number(r)) => self.stack.push(Value::Number(l + r)),
+                        (Value::String(l), Value::String(r)) => self.stack.push(Value::String(l + &r)),
+                        _ => return self.error("+", "Operands must be either two numbers or two strings."),
+                    }
+                },
+                OpCode::Subtract => {
+                    let (left, right) = self.pop_numbers("-")?;
+                    self.stack.push(Value::Number(left - right));
+                },
+                OpCode::Multiply => {
+                    let (left, right) = self.pop_numbers("*")?;
+                    self.stack.push(Value::Number(left * right));
+                },
+                OpCode::Divide => {
+                    let (left, right) = self.pop_numbers("/")?;
+                    self.stack.push(Value::Number(left / right));
+                },
+                OpCode::Modulo => {
+                    let (left, right) = self.pop_numbers("%")?;
+                    self.stack.push(Value::Number(left % right));
+                },
+                OpCode::Power => {
+                    let (left, right) = self.pop_numbers("**")?;
+                    self.stack.push(Value::Number(left.powf(right)));
+                },
+                OpCode::IntegerDivide => {
+                    let (left, right) = self.pop_numbers("~/")?;
+                    match util::integer_divide(left, right) {
+                        Ok(result) => self.stack.push(Value::Number(result)),
+                        Err(message) => return self.error("~/", message),
+                    }
+                },
+                OpCode::BitwiseAnd => self.integer_operation("&", IntegerOperator::And)?,
+                OpCode::BitwiseOr => self.integer_operation("|", IntegerOperator::Or)?,
+                OpCode::BitwiseXor => self.integer_operation("^", IntegerOperator::Xor)?,
+                OpCode::ShiftLeft => self.integer_operation("<<", IntegerOperator::ShiftLeft)?,
+                OpCode::ShiftRight => self.integer_operation(">>", IntegerOperator::ShiftRight)?,
+                OpCode::BitwiseNot => {
+                    let result = match self.pop() {
+                        Value::Number(n) => util::bitwise_not(n),
+                        _ => return self.error("~", "Operand must be a number."),
+                    };
+                    match result {
+                        Ok(result) => self.stack.push(Value::Number(result)),
+                        Err(message) => return self.error("~", message),
+                    }
+                },
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(value.not());
+                },
+                OpCode::Negate => {
+                    match self.pop() {
+                        Value::Number(n) => self.stack.push(Value::Number(-n)),
+                        _ => return self.error("-", "Operand must be a number."),
+                    }
+                },
+                OpCode::Print => println!("{}", self.pop()),
+                OpCode::Jump => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip += offset;
+                },
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short() as usize;
+                    if !self.peek(0).is_truthy() {
+                        self.frame_mut().ip += offset;
+                    }
+                },
+                OpCode::Loop => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip -= offset;
+                },
+                OpCode::Call => {
+                    let argument_count = self.read_byte() as usize;
+                    self.call_value(argument_count)?;
+                },
+                OpCode::Closure => {
+                    let index = self.read_short() as usize;
+                    let enclosing = self.frame().closure.clone();
+                    let function = enclosing.function.chunk.functions[index].clone();
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().base + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(enclosing.upvalues[index].clone());
+                        }
+                    }
+                    self.stack.push(Value::Callable(gc::new_callable(VmClosure {
+                        function,
+                        upvalues,
+                        globals: enclosing.globals.clone(),
+                        receiver: None,
+                    })));
+                },
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                },
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    if self.frames.len() == stop_depth {
+                        return Ok(result);
+                    }
+                    self.stack.push(result);
+                },
+                OpCode::Class => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let method_count = self.read_short() as usize;
+                    let methods = self.pop_methods(method_count);
+                    self.stack.push(Value::Callable(gc::new_callable(LoxClass::new(name.to_string(), None, methods))));
+                },
+                OpCode::Subclass => {
+                    let closure = self.frame().closure.clone();
+                    let name = self.read_name(&closure.function.chunk);
+                    let superclass_name = self.read_name(&closure.function.chunk);
+                    let method_count = self.read_short() as usize;
+                    let methods = self.pop_methods(method_count);
+                    // The superclass stays on the stack, as the local 'super' that methods close over.
+                    let superclass = match self.peek(0) {
+                        Value::Callable(callable) => callable.clone().as_class(),
+                        _ => None,
+                    };
+                    if superclass.is_none() {
+                        return self.error(superclass_name, "Superclass must be a class.");
+                    }
+                    self.stack.push(Value::Callable(gc::new_callable(LoxClass::new(name.to_string(), superclass, methods))));
+                },
+                OpCode::List => {
+                    let count = self.read_short() as usize;
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(Value::List(gc::new_list(elements)));
+                },
+                OpCode::Template => {
+                    let count = self.read_short() as usize;
+                    let parts = self.stack.split_off(self.stack.len() - count);
+                    let string: String = parts.iter().map(|part| part.to_string()).collect();
+                    self.stack.push(Value::String(string));
+                },
+                OpCode::Map => {
+                    let count = self.read_short() as usize;
+                    let entries = self.stack.split_off(self.stack.len() - count * 2);
+                    let mut map = LoxMap::new();
+                    let mut entries = entries.into_iter();
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        match MapKey::new(key) {
+                            Ok(key) => map.insert(key, value),
+                            Err(message) => return self.error("{", &message),
+                        }
+                    }
+                    self.stack.push(Value::Map(gc::new_map(map)));
+                },
+                OpCode::PushCatch => self.push_handler(false),
+                OpCode::PushFinally => self.push_handler(true),
+                OpCode::PopHandler => {
+                    self.handlers.pop().expect("A handler was popped that was never pushed!");
+                },
+                OpCode::Throw => {
+                    let value = self.pop();
+                    return Err(Diagnostic::thrown(self.span(), value));
+                },
+                OpCode::Rethrow => {
+                    self.pop();
+                    let slot = self.stack.len();
+                    let index = self.raised.iter().rposition(|(raised_slot, _)| *raised_slot == slot).expect("A rethrow with nothing to raise snuck in!");
+                    let (_, diagnostic) = self.raised.remove(index);
+                    return Err(diagnostic);
+                },
+            }
+        }
+    }
+
+    // The handler's code starts the given distance past its operand.
+    fn push_handler(&mut self, finally: bool) {
+        let offset = self.read_short() as usize;
+        let ip = self.frame().ip + offset;
+        self.handlers.push(Handler {
+            frame_count: self.frames.len(),
+            stack_len: self.stack.len(),
+            ip,
+            finally,
+        });
+    }
+
+    fn call_value(&mut self, argument_count: usize) -> Result<(), Diagnostic> {
+        let callee = self.peek(argument_count).clone();
+        match callee {
+            Value::Callable(callable) => {
+                if let Some(class) = callable.clone().as_class() {
+                    return self.call_class(class, argument_count);
+                }
+                if let Some(closure) = callable.clone().as_vm_closure() {
+                    return self.call_closure(closure, argument_count);
+                }
+                if !callable.arity().accepts(argument_count) {
+                    return self.error(")", &format!("Expected {} arguments but got {}.", callable.arity(), argument_count));
+                }
+                let arguments = self.stack.split_off(self.stack.len() - argument_count);
+                self.pop();
+                let span = self.span();
+                let result = callable.call(arguments).map_err(|diagnostic| diagnostic.or_at(span))?;
+                self.stack.push(result);
+                Ok(())
+            },
+            _ => self.error(")", "Can only call functions and classes."),
+        }
+    }
+
+    fn call_class(&mut self, class: Rc<LoxClass>, argument_count: usize) -> Result<(), Diagnostic> {
+        let instance = Value::Instance(gc::new_instance(LoxInstance::new(class.clone())));
+        let base = self.stack.len() - argument_count - 1;
+        self.stack[base] = instance;
+        if let Some(init) = class.find_method("init") {
+            let init = init.clone().as_vm_closure().expect("A method from outside the VM snuck in!");
+            self.call_closure(init, argument_count)
+        } else if argument_count != 0 {
+            self.error(")", &format!("Expected 0 arguments but got {}.", argument_count))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn call_closure(&mut self, closure: Rc<VmClosure>, argument_count: usize) -> Result<(), Diagnostic> {
+        if argument_count != closure.function.arity {
+            return self.error(")", &format!("Expected {} arguments but got {}.", closure.function.arity, argument_count));
+        }
+        if self.frames.len() >= MAXIMUM_FRAME_COUNT {
+            return Err(Diagnostic::error(Some(self.span()), "Stack overflow."));
+        }
+        let base = self.stack.len() - argument_count - 1;
+        if let Some(receiver) = &closure.receiver {
+            self.stack[base] = receiver.clone();
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base,
+        });
+        Ok(())
+    }
+
+    fn pop_methods(&mut self, count: usize) -> HashMap<String, Rc<dyn Method>> {
+        let mut methods: HashMap<String, Rc<dyn Method>> = HashMap::new();
+        for method in self.stack.split_off(self.stack.len() - count) {
+            match method {
+                Value::Callable(callable) => {
+                    let closure = callable.as_vm_closure().expect("An invalid method snuck in!");
+                    methods.insert(closure.function.name.clone(), closure);
+                },
+                _ => panic!("An invalid method snuck in!"),
+            }
+        }
+        methods
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if let Upvalue::Open(open_slot) = *upvalue.borrow() {
+                if open_slot == slot {
+                    return upvalue.clone();
+                }
+            }
+        }
+        let upvalue = gc::new_upvalue(Upvalue::Open(slot));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    // Moves every local at or above the given slot off the stack and into its upvalue.
+    fn close_upvalues(&mut self, from_slot: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => return false,
+            };
+            if slot >= from_slot {
+                *upvalue.borrow_mut() = Upvalue::Closed(stack[slot].clone());
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn pop_numbers(&mut self, operator: &str) -> Result<(f64, f64), Diagnostic> {
+        let right = self.pop();
+        let left = self.pop();
+        match (left, right) {
+            (Value::Number(l), Value::Number(r)) => Ok((l, r)),
+            _ => self.error(operator, "Operand must be a number."),
+        }
+    }
+
+    fn integer_operation(&mut self, operator: &str, integer_operator: IntegerOperator) -> Result<(), Diagnostic> {
+        let (left, right) = self.pop_numbers(operator)?;
+        match util::integer_operation(integer_operator, left, right) {
+            Ok(result) => {
+                self.stack.push(Value::Number(result));
+                Ok(())
+            },
+            Err(message) => self.error(operator, message),
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let frame = self.frames.last_mut().unwrap();
+        let value = frame.closure.function.chunk.read_short(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_name<'a>(&mut self, chunk: &'a Chunk) -> &'a str {
+        match &chunk.constants[self.read_short() as usize] {
+            Value::String(name) => name,
+            _ => panic!("An invalid name constant snuck in!"),
+        }
+    }
+
+    // Built-in methods report their errors on a token, so we make one up for them.
+    fn name_token(&self, name: &str) -> Token {
+        Token::with_span(token::Type::Identifier, name.to_string(), self.span())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("The VM stack underflowed!")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn span(&self) -> Span {
+        let frame = self.frame();
+        frame.closure.function.chunk.span(frame.ip.saturating_sub(1))
+    }
+
+    fn error<T>(&self, lexeme: &str, message: &str) -> Result<T, Diagnostic> {
+        Err(Diagnostic::at(self.span(), lexeme, message))
+    }
+}
@@ -0,0 +1,42 @@
+// Runs the annotated scripts in tests/lox on both backends. The same runner can be pointed at the
+// Crafting Interpreters suite with `lox-rs test path/to/craftinginterpreters/test`.
+
+use std::path::{Path, PathBuf};
+
+use lox_rs::conformance::{Outcome, Runner};
+
+fn run_suite(arguments: &[&str]) {
+    let runner = Runner::new(PathBuf::from(env!("CARGO_BIN_EXE_lox-rs")), arguments.iter().map(|argument| argument.to_string()).collect());
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("lox");
+    let summary = runner.run_directory(&directory, |_, _| {}).expect("Failed to run the test scripts");
+    let report: Vec<String> = summary.failures.iter().map(|(path, failures)| format!("{}:\n  {}", path.display(), failures.join("\n  "))).collect();
+    assert!(summary.is_success(), "{} of {} scripts failed:\n{}", summary.failures.len(), summary.failures.len() + summary.passed, report.join("\n"));
+    assert!(summary.passed > 0, "No test scripts were found in {}", directory.display());
+}
+
+#[test]
+fn tree_walker() {
+    run_suite(&[]);
+}
+
+#[test]
+fn vm() {
+    run_suite(&["--vm"]);
+}
+
+#[test]
+fn vm_under_gc_stress() {
+    run_suite(&["--vm", "--gc-stress"]);
+}
+
+#[test]
+fn expectations_are_checked() {
+    let runner = Runner::new(PathBuf::from(env!("CARGO_BIN_EXE_lox-rs")), Vec::new());
+    let directory = std::env::temp_dir().join(format!("lox-rs-conformance-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let script = directory.join("wrong.lox");
+    std::fs::write(&script, "print 1; // expect: 2\n").unwrap();
+    let outcome = runner.run_file(&script).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(outcome, Outcome::Fail(vec!["Expected output '2' on line 1 and got '1'.".to_string()]));
+}
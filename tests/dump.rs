@@ -0,0 +1,15 @@
+// Checks what `--dump-ast` and `--dump-resolved` print for a few constructs.
+
+use lox_rs::{Backend, Dump, Interpreter, Lox};
+
+fn dump(source: &str, dump: Dump) -> String {
+    let mut lox = Lox::new(Backend::TreeWalker(Interpreter::new()));
+    lox.dump("<test>", source, dump).expect("Failed to dump the source")
+}
+
+#[test]
+fn property_access() {
+    let source = "class Point { move(x) { this.x = this.x + x; } }";
+    assert_eq!(dump(source, Dump::Ast), "(class Point (fun move (x) (; (property set this x (+ (property this x) (variable x))))))\n");
+    assert_eq!(dump(source, Dump::Resolved), "(class Point (fun move (x) (; (property set this @1:0 x (+ (property this @1:0 x) (variable x @0:0))))))\n");
+}
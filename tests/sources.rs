@@ -0,0 +1,48 @@
+// Spans hold on to the source they're in, so it's dropped once nothing can refer to it, and errors
+// always quote the source they actually came from.
+
+use std::rc::Rc;
+
+use lox_rs::{Backend, Interpreter, Lox, LoxError, Value, Vm};
+
+fn backends() -> Vec<Backend> {
+    vec![Backend::TreeWalker(Interpreter::new()), Backend::Vm(Vm::new())]
+}
+
+#[test]
+fn errors_in_earlier_functions_still_quote_their_source() {
+    for backend in backends() {
+        let mut lox = Lox::new(backend);
+        lox.evaluate("fun fail() {\n  return 1 + nil;\n}").unwrap();
+        for _ in 0..10 {
+            assert_eq!(lox.evaluate("1 + 2;").unwrap(), Value::Number(3.0));
+        }
+        let error = lox.evaluate("fail();").unwrap_err();
+        let rendered = lox.render(&error);
+        assert!(rendered.contains("2 |   return 1 + nil;"), "{}", rendered);
+    }
+}
+
+#[test]
+fn errors_in_native_methods_quote_where_they_were_looked_up() {
+    for backend in backends() {
+        let mut lox = Lox::new(backend);
+        lox.evaluate("var s = \"abcdef\".slice;").unwrap();
+        lox.evaluate("var zzzzzzzzzzzzzzzzzzzz = 1;").unwrap();
+        let error = lox.evaluate("s(\"x\", 1);").unwrap_err();
+        let rendered = lox.render(&error);
+        assert!(rendered.contains("1 | var s = \"abcdef\".slice;\n  |                  ^^^^^"), "{}", rendered);
+    }
+}
+
+#[test]
+fn sources_are_dropped_with_the_last_error_quoting_them() {
+    for backend in backends() {
+        let mut lox = Lox::new(backend);
+        let source = match lox.evaluate("1 + nil;").unwrap_err() {
+            LoxError::Runtime(diagnostic) => Rc::downgrade(diagnostic.span.as_ref().and_then(|span| span.source.as_ref()).unwrap()),
+            error => panic!("expected a runtime error, got {}", error),
+        };
+        assert!(source.upgrade().is_none());
+    }
+}
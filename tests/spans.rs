@@ -0,0 +1,18 @@
+// Both backends should point runtime errors at the same place in the source.
+
+use lox_rs::{Backend, Interpreter, Lox, LoxError, Vm};
+
+fn error_column(backend: Backend, source: &str) -> usize {
+    let mut lox = Lox::new(backend);
+    match lox.evaluate(source) {
+        Err(LoxError::Runtime(diagnostic)) => diagnostic.span.as_ref().expect("runtime errors have a span").column,
+        _ => panic!("expected a runtime error"),
+    }
+}
+
+#[test]
+fn superclass_errors_point_at_the_superclass() {
+    let source = "var NotAClass = 1;\nclass Subclass < NotAClass {}";
+    assert_eq!(error_column(Backend::TreeWalker(Interpreter::new()), source), 18);
+    assert_eq!(error_column(Backend::Vm(Vm::new()), source), 18);
+}